@@ -1,20 +1,20 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
-use cosmwasm_std::{from_binary, Addr};
-use cosmwasm_std::{to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Storage, Api, Querier, BankMsg, Coin};
+use cosmwasm_std::{to_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult};
 use cw2::set_contract_version;
-use cw20::Cw20ReceiveMsg;
 
 use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg, Cw20HookMsg};
-use crate::execute::{execute_escrow, execute_redeem, lend_to_pool, borrow_from_pool, earn_tokens_into_pool, withdraw_from_pool_for_earn};
-use crate::query::{ query_escrow, query_borrow_to_pool, query_pool};
+use crate::msg::{ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
+use crate::migrate::migrate_contract;
+use crate::execute::{execute_redeem, borrow_from_pool, borrow_with_quote, release_from_pool, withdraw_from_pool_for_earn, validate_config, update_config, propose_new_owner, cancel_ownership_proposal, accept_ownership, grant_role, revoke_role, withdraw_fees, settle_backend_trade, create_maturity, settle_maturity, liquidate, set_collateral_asset, set_oracle, set_backend_pubkey, withdraw_collateral, pause, unpause};
+use crate::state::{CollateralAsset, Config, InterestIndex, OracleConfig, CONFIG, INTEREST_INDEX};
+use crate::query::{ query_escrow, query_lend_to_pool, query_borrow_to_pool, query_pool, query_config, query_ownership_proposal, query_maturities, query_collateral, query_collaterals, query_collateral_assets, query_accepted_tokens, query_oracle, query_health_factor, query_rates, query_pause_state, query_role_holders, query_collected_fees, query_earn_pool, query_earn_balance, query_convert_to_shares, query_convert_to_assets};
 
-use self::execute::{receive_cw20, receive_cw20_to_pool, borrow_cw20_from_pool, earn_to_pool};
+use self::execute::receive_cw20;
 
 // version info for migration info
-const CONTRACT_NAME: &str = "Temporal AMM Contracts";
-const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub(crate) const CONTRACT_NAME: &str = "Temporal AMM Contracts";
+pub(crate) const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
@@ -24,9 +24,37 @@ pub fn instantiate(
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    let owner = match msg.admin {
+        Some(admin) => deps.api.addr_validate(&admin)?,
+        None => info.sender,
+    };
+    let config = Config {
+        owner,
+        token: deps.api.addr_validate(&msg.token)?,
+        base_interest_rate: msg.base_interest_rate,
+        slope1: msg.slope1,
+        slope2: msg.slope2,
+        optimal_utilization: msg.optimal_utilization,
+        fee_percentage: msg.fee_percentage,
+        min_duration: msg.min_duration,
+        max_duration: msg.max_duration,
+        max_borrow_amount: msg.max_borrow_amount,
+        close_factor: msg.close_factor,
+        close_factor_dust: msg.close_factor_dust,
+        liquidation_bonus: msg.liquidation_bonus,
+        backend_pubkey: msg.backend_pubkey,
+        term_rates: msg.term_rates,
+    };
+    validate_config(&config)?;
+    CONFIG.save(deps.storage, &config)?;
+    INTEREST_INDEX.save(deps.storage, &InterestIndex::new(_env.block.time.seconds()))?;
  
     Ok(Response::new().add_attribute("method", "instantiate")
-                      .add_attribute("owner", info.sender))
+                      .add_attribute("owner", config.owner)
+                      .add_attribute("token", config.token)
+                      .add_attribute("base_interest_rate", config.base_interest_rate.to_string())
+                      .add_attribute("fee_percentage", config.fee_percentage.to_string()))
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -37,164 +65,78 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::ReceiveForCollateral(msg) => receive_cw20(deps, _env, info, msg),
-        ExecuteMsg::RedeemForCollateral{} => execute_redeem(deps, _env, info.sender),
-        ExecuteMsg::LendToPool(msg) =>receive_cw20_to_pool(deps, _env, info, msg),
-        ExecuteMsg::BorrowFromPool(msg) =>borrow_cw20_from_pool(deps, _env, info, msg),
-        ExecuteMsg::EarnToPool(msg) =>earn_to_pool(deps, _env, info, msg),
-        ExecuteMsg::Increment {} => execute::increment(deps),
-        ExecuteMsg::Reset { count } => execute::reset(deps, info, count),
-        ExecuteMsg::LendToPoolV2 {lender, amount, duration } => execute::lend_to_poolV2(deps, _env, lender, amount, duration),
+        ExecuteMsg::Receive(msg) => receive_cw20(deps, _env, info, msg),
+        ExecuteMsg::RedeemForCollateral{ escrow_id } => execute_redeem(deps, _env, info.sender, escrow_id),
+        ExecuteMsg::BorrowFromPool { amount, maturity_date, quote: None } => borrow_from_pool(deps, _env, info.sender, amount, maturity_date),
+        ExecuteMsg::BorrowFromPool { amount, maturity_date, quote: Some(quote) } => {
+            borrow_with_quote(deps, _env, info.sender, amount, maturity_date, quote)
+        }
+        ExecuteMsg::WithdrawMatured { position_id } => release_from_pool(deps, _env, info.sender, position_id),
+        ExecuteMsg::WithdrawEarn { amount } => withdraw_from_pool_for_earn(deps, info.sender, amount),
+        ExecuteMsg::SettleBackendTrade { lender, amount, maturity_date, backing, quote } => settle_backend_trade(deps, _env, info.sender, lender, amount, maturity_date, backing, quote),
+        ExecuteMsg::Liquidate { borrower, loan_id, repay_amount } => liquidate(deps, _env, info.sender, borrower, loan_id, repay_amount),
+        ExecuteMsg::SettleMaturity { maturity_date } => settle_maturity(deps, _env, maturity_date),
+        ExecuteMsg::CreateMaturity { maturity_date } => create_maturity(deps, _env, info.sender, maturity_date),
+        ExecuteMsg::WithdrawCollateral { asset, amount } => withdraw_collateral(deps, _env, info.sender, asset, amount),
+        ExecuteMsg::SetCollateralAsset { asset, price, max_ltv, liquidation_threshold } => {
+            set_collateral_asset(deps, info.sender, asset, CollateralAsset { price, max_ltv, liquidation_threshold })
+        }
+        ExecuteMsg::SetOracle { oracle, max_price_age, max_price_deviation } => {
+            let address = deps.api.addr_validate(&oracle)?;
+            set_oracle(deps, info.sender, OracleConfig { address, max_price_age, max_price_deviation })
+        }
+        ExecuteMsg::SetBackendPubkey { pubkey } => set_backend_pubkey(deps, info.sender, pubkey),
+        ExecuteMsg::UpdateConfig(update) => update_config(deps, info.sender, *update),
+        ExecuteMsg::ProposeNewOwner { new_owner, expires_in } => propose_new_owner(deps, _env, info.sender, new_owner, expires_in),
+        ExecuteMsg::CancelOwnershipProposal {} => cancel_ownership_proposal(deps, info.sender),
+        ExecuteMsg::AcceptOwnership {} => accept_ownership(deps, _env, info.sender),
+        ExecuteMsg::GrantRole { role, address } => grant_role(deps, info.sender, role, address),
+        ExecuteMsg::RevokeRole { role, address } => revoke_role(deps, info.sender, role, address),
+        ExecuteMsg::Pause { action } => pause(deps, info.sender, action),
+        ExecuteMsg::Unpause { action } => unpause(deps, info.sender, action),
+        ExecuteMsg::WithdrawFees { amount } => withdraw_fees(deps, info.sender, amount),
     }
 }
 
 pub mod execute {
-    use cosmwasm_std::{DepsMut, Env, MessageInfo, Response, from_binary, Addr, Uint128};
+    use cosmwasm_std::{DepsMut, Env, MessageInfo, Response, from_binary};
     use cw20::Cw20ReceiveMsg;
 
-    use crate::{ContractError, msg::Cw20HookMsg, execute::{execute_escrow, lend_to_pool, borrow_from_pool, earn_tokens_into_pool, withdraw_from_pool_for_earn}, state::{STATE, VAULT, Vault, LenderInfo, LENDERS}};
-
-    pub fn increment(deps: DepsMut) -> Result<Response, ContractError> {
-        STATE.update(deps.storage, |mut state| -> Result<_, ContractError> {
-            state.count += 1;
-            Ok(state)
-        })?;
-
-        Ok(Response::new().add_attribute("action", "increment"))
-    }
+    use crate::{ContractError, msg::Cw20HookMsg, execute::{assert_accepted_token, deposit_collateral, execute_escrow, lend_to_pool, lend_with_quote, earn_tokens_into_pool, repay_loan}};
 
-    pub fn reset(deps: DepsMut, info: MessageInfo, count: i32) -> Result<Response, ContractError> {
-        STATE.update(deps.storage, |mut state| -> Result<_, ContractError> {
-            if info.sender != state.owner {
-                return Err(ContractError::Unauthorized {});
-            }
-            state.count = count;
-            Ok(state)
-        })?;
-        Ok(Response::new().add_attribute("action", "reset"))
-    }
-
-    pub fn lend_to_poolV2(
+    // Single entry point for Cw20 `Send`, dispatching on the attached hook message
+    pub fn receive_cw20(
         deps: DepsMut,
         env: Env,
-        lender: Addr,
-        amount: Uint128,
-        duration: u64
+        info: MessageInfo,
+        cw20_msg: Cw20ReceiveMsg,
     ) -> Result<Response, ContractError> {
-        let mut vault = match VAULT.load(deps.storage) {
-            Ok(v) => v,
-            Err(_) => Vault::default(), // Use the default if not present in storage
-        };
-        
-        vault.total_tokens += amount;
-        VAULT.save(deps.storage, &vault)?;
-    
-        let lender_info = LenderInfo {
-            lender: lender.clone(),
-            amount_lent: amount,
-            maturity_date: env.block.time.seconds() + duration,
-        };
-        LENDERS.save(deps.storage, &lender_info)?;
-    
-        Ok(Response::default().add_attribute("action", "lend"))
-    }
-
-
-
-        pub fn receive_cw20(
-            deps: DepsMut,
-            env: Env,
-            info: MessageInfo,
-            cw20_msg: Cw20ReceiveMsg,
-        ) -> Result<Response, ContractError> {
-            match from_binary(&cw20_msg.msg) {
-                Ok(Cw20HookMsg::Escrow { time }) => execute_escrow(
-                    deps,
-                    env,
-                    Addr::unchecked(cw20_msg.sender),
-                    info.sender,
-                    cw20_msg.amount,
-                    time,
-                ),
-                Err(err) => Err(ContractError::Std(err)),
-            }
-        }
-
-        pub fn receive_cw20_to_pool(
-            deps: DepsMut,
-            env: Env,
-            info: MessageInfo,
-            msg: Cw20ReceiveMsg,
-        ) -> Result<Response, ContractError> {
-            match from_binary(&msg.msg) {
-                Ok(Cw20HookMsg::Escrow { time }) => lend_to_pool(
-                    deps,
-                    env,
-                    Addr::unchecked(msg.sender),
-                    msg.amount,
-                    time,
-                ),
-                Err(err) => Err(ContractError::Std(err)),
-            }
-        }
-
-        pub fn borrow_cw20_from_pool(
-            deps: DepsMut,
-            env: Env,
-            info: MessageInfo,
-            cw20_msg: Cw20ReceiveMsg,
-        ) -> Result<Response, ContractError> {
-            match from_binary(&cw20_msg.msg) {
-                Ok(Cw20HookMsg::Escrow { time }) => borrow_from_pool(
-                    deps,
-                    env,
-                    Addr::unchecked(cw20_msg.sender),
-                    cw20_msg.amount,
-                    time,
-                ),
-                Err(err) => Err(ContractError::Std(err)),
-            }
-        }
-
-        pub fn earn_to_pool(
-            deps: DepsMut,
-            env: Env,
-            info: MessageInfo,
-            cw20_msg: Cw20ReceiveMsg,
-        ) -> Result<Response, ContractError> {
-            match from_binary(&cw20_msg.msg) {
-                Ok(Cw20HookMsg::Escrow { time }) => earn_tokens_into_pool(
-                    deps,
-                    env,
-                    Addr::unchecked(cw20_msg.sender),
-                    cw20_msg.amount,
-                ),
-                Err(err) => Err(ContractError::Std(err)),
-            }
-        }
+        let hook: Cw20HookMsg = from_binary(&cw20_msg.msg)?;
+        // Only a token contract accepted for the hook can tell us tokens were deposited
+        assert_accepted_token(deps.storage, &info.sender, &hook)?;
 
-        //function to release tokens from withdraw from earn
-        pub fn withdraw_for_earn(
-            deps: DepsMut,
-            env: Env,
-            info: MessageInfo,
-            cw20_msg: Cw20ReceiveMsg,
-        ) -> Result<Response, ContractError> {
-            match from_binary(&cw20_msg.msg) {
-                Ok(Cw20HookMsg::Escrow { time }) =>withdraw_from_pool_for_earn (
-                    deps,
-                    env,
-                    Addr::unchecked(cw20_msg.sender),
-                ),
-                Err(err) => Err(ContractError::Std(err)),
+        let sender = deps.api.addr_validate(&cw20_msg.sender)?;
+        match hook {
+            Cw20HookMsg::Lend { maturity_date, quote: None } => lend_to_pool(deps, env, sender, cw20_msg.amount, maturity_date),
+            Cw20HookMsg::Lend { maturity_date, quote: Some(quote) } => {
+                lend_with_quote(deps, env, sender, cw20_msg.amount, maturity_date, quote)
             }
+            Cw20HookMsg::Earn {} => earn_tokens_into_pool(deps, sender, cw20_msg.amount),
+            Cw20HookMsg::Escrow { time } => execute_escrow(deps, env, sender, info.sender, cw20_msg.amount, time),
+            Cw20HookMsg::Repay { loan_id } => repay_loan(deps, env, sender, loan_id, cw20_msg.amount),
+            Cw20HookMsg::Collateral {} => deposit_collateral(deps, sender, info.sender, cw20_msg.amount),
         }
+    }
 
+}
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    migrate_contract(deps, env, msg)
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::Escrow { address } => {
             to_binary(&query_escrow(deps, deps.api.addr_validate(&address)?)?)
@@ -203,9 +145,30 @@ pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
             to_binary(&query_borrow_to_pool(deps, deps.api.addr_validate(&address)?)?)   
         }
         QueryMsg::LendToPool { address } => {
-            to_binary(&query_borrow_to_pool(deps, deps.api.addr_validate(&address)?)?)   
+            to_binary(&query_lend_to_pool(deps, env, deps.api.addr_validate(&address)?)?)
         }
         QueryMsg::Pool {} => to_binary(&query_pool(deps)?),
+        QueryMsg::Config {} => to_binary(&query_config(deps)?),
+        QueryMsg::OwnershipProposal {} => to_binary(&query_ownership_proposal(deps)?),
+        QueryMsg::Maturities {} => to_binary(&query_maturities(deps)?),
+        QueryMsg::Collateral { address, asset } => {
+            to_binary(&query_collateral(deps, deps.api.addr_validate(&address)?, deps.api.addr_validate(&asset)?)?)
+        }
+        QueryMsg::Collaterals { address } => to_binary(&query_collaterals(deps, deps.api.addr_validate(&address)?)?),
+        QueryMsg::CollateralAssets {} => to_binary(&query_collateral_assets(deps)?),
+        QueryMsg::AcceptedTokens {} => to_binary(&query_accepted_tokens(deps)?),
+        QueryMsg::Oracle {} => to_binary(&query_oracle(deps)?),
+        QueryMsg::HealthFactor { address } => to_binary(&query_health_factor(deps, env, deps.api.addr_validate(&address)?)?),
+        QueryMsg::Rates {} => to_binary(&query_rates(deps, env)?),
+        QueryMsg::PauseState {} => to_binary(&query_pause_state(deps)?),
+        QueryMsg::RoleHolders { role } => to_binary(&query_role_holders(deps, role)?),
+        QueryMsg::CollectedFees {} => to_binary(&query_collected_fees(deps)?),
+        QueryMsg::EarnPool {} => to_binary(&query_earn_pool(deps)?),
+        QueryMsg::EarnBalance { address } => {
+            to_binary(&query_earn_balance(deps, deps.api.addr_validate(&address)?)?)
+        }
+        QueryMsg::ConvertToShares { assets } => to_binary(&query_convert_to_shares(deps, assets)?),
+        QueryMsg::ConvertToAssets { shares } => to_binary(&query_convert_to_assets(deps, shares)?),
     }
 }
 #[cfg(test)]
@@ -213,16 +176,350 @@ mod tests {
 
     use super::*;
     use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
-    use cosmwasm_std::{coins, from_binary};
+    use cosmwasm_std::{coins, from_binary, Addr, Decimal, Decimal256, Timestamp, Uint128};
+    use crate::execute::{earn_tokens_into_pool, lend_to_pool};
+    use crate::msg::{AcceptedTokensResponse, Cw20HookMsg, EarnBalanceResponse, EarnConversionResponse, EarnPoolResponse, HealthFactorResponse, LenderPoolResponse, MaturitiesResponse, RatesResponse};
+    use cw20::Cw20ReceiveMsg;
+    use crate::state::{TermRate, SECONDS_PER_YEAR};
+    fn mock_instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            admin: None,
+            token: "token".to_string(),
+            base_interest_rate: Decimal::percent(10),
+            slope1: Decimal::percent(4),
+            slope2: Decimal::percent(60),
+            optimal_utilization: Decimal::percent(80),
+            fee_percentage: Decimal::percent(10),
+            min_duration: 60,
+            max_duration: SECONDS_PER_YEAR,
+            max_borrow_amount: Uint128::new(1_000_000),
+            close_factor: Decimal::percent(50),
+            close_factor_dust: Uint128::new(10),
+            liquidation_bonus: Decimal::percent(10),
+            backend_pubkey: None,
+            term_rates: vec![TermRate { duration: 60, rate: Decimal::percent(5) }],
+        }
+    }
+
+    // Registers the pool token as collateral at par and posts `amount` of it for `borrower`
+    fn post_collateral(mut deps: DepsMut, borrower: &str, amount: u128) {
+        let register = ExecuteMsg::SetCollateralAsset {
+            asset: "token".to_string(),
+            price: Decimal::one(),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        execute(deps.branch(), mock_env(), mock_info("creator", &[]), register).unwrap();
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: borrower.to_string(),
+            amount: Uint128::new(amount),
+            msg: to_binary(&Cw20HookMsg::Collateral {}).unwrap(),
+        });
+        execute(deps, mock_env(), mock_info("token", &[]), msg).unwrap();
+    }
+
     #[test]
     fn proper_initialization() {
         let mut deps = mock_dependencies();
         let info = mock_info("creator", &coins(1000, "BTC"));
-        let msg = InstantiateMsg {  };
+        let msg = InstantiateMsg {
+            base_interest_rate: Decimal::percent(5),
+            ..mock_instantiate_msg()
+        };
         // we can just call .unwrap() to assert this was a success
         let res = instantiate(deps.as_mut(), mock_env(), info, msg).unwrap();
         assert_eq!(0, res.messages.len());
 
+        // it worked, let's query the config
+        let config: Config = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Config {}).unwrap()).unwrap();
+        assert_eq!(config, Config {
+            owner: Addr::unchecked("creator"),
+            token: Addr::unchecked("token"),
+            base_interest_rate: Decimal::percent(5),
+            slope1: Decimal::percent(4),
+            slope2: Decimal::percent(60),
+            optimal_utilization: Decimal::percent(80),
+            fee_percentage: Decimal::percent(10),
+            min_duration: 60,
+            max_duration: SECONDS_PER_YEAR,
+            max_borrow_amount: Uint128::new(1_000_000),
+            close_factor: Decimal::percent(50),
+            close_factor_dust: Uint128::new(10),
+            liquidation_bonus: Decimal::percent(10),
+            backend_pubkey: None,
+            term_rates: vec![TermRate { duration: 60, rate: Decimal::percent(5) }],
+        });
+
+    }
+
+    #[test]
+    fn initialization_with_admin_and_invalid_config() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            admin: Some("multisig".to_string()),
+            ..mock_instantiate_msg()
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        assert_eq!(CONFIG.load(deps.as_ref().storage).unwrap().owner, "multisig");
+
+        let msg = InstantiateMsg {
+            fee_percentage: Decimal::percent(101),
+            ..mock_instantiate_msg()
+        };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidConfig { .. }));
+
+        let msg = InstantiateMsg {
+            min_duration: SECONDS_PER_YEAR + 1,
+            ..mock_instantiate_msg()
+        };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidConfig { .. }));
+    }
+
+    #[test]
+    fn receive_rejects_spoofed_token() {
+        let mut deps = mock_dependencies();
+        let msg = mock_instantiate_msg();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+        let hooks = vec![
+            Cw20HookMsg::Lend { maturity_date: 60, quote: None },
+            Cw20HookMsg::Earn {},
+            Cw20HookMsg::Escrow { time: 60 },
+            Cw20HookMsg::Repay { loan_id: 1 },
+            Cw20HookMsg::Collateral {},
+        ];
+        for hook in hooks {
+            // A contract that is not the pool token claims a deposit on behalf of a user
+            let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: "attacker".to_string(),
+                amount: Uint128::new(1_000),
+                msg: to_binary(&hook).unwrap(),
+            });
+            let err = execute(deps.as_mut(), mock_env(), mock_info("fake_token", &[]), msg).unwrap_err();
+            assert!(matches!(err, ContractError::UnacceptedToken { token } if token == "fake_token"));
+        }
+
+        // Nothing was credited
+        assert!(crate::state::VAULT.may_load(deps.as_ref().storage).unwrap().is_none());
+
+        // Each token is only accepted for its own hooks
+        let set_atom = ExecuteMsg::SetCollateralAsset {
+            asset: "atom".to_string(),
+            price: Decimal::one(),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), set_atom).unwrap();
+        let receive = |hook: &Cw20HookMsg| {
+            ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: "attacker".to_string(),
+                amount: Uint128::new(1_000),
+                msg: to_binary(hook).unwrap(),
+            })
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("atom", &[]), receive(&Cw20HookMsg::Earn {})).unwrap_err();
+        assert!(matches!(err, ContractError::UnacceptedToken { token } if token == "atom"));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("token", &[]), receive(&Cw20HookMsg::Collateral {})).unwrap_err();
+        assert!(matches!(err, ContractError::UnacceptedToken { token } if token == "token"));
+        execute(deps.as_mut(), mock_env(), mock_info("atom", &[]), receive(&Cw20HookMsg::Collateral {})).unwrap();
+
+        let accepted: AcceptedTokensResponse =
+            from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::AcceptedTokens {}).unwrap()).unwrap();
+        assert_eq!(
+            accepted,
+            AcceptedTokensResponse { pool_token: Addr::unchecked("token"), collateral_assets: vec![Addr::unchecked("atom")] }
+        );
+    }
+
+    #[test]
+    fn earn_pool_queries() {
+        let mut deps = mock_dependencies();
+        let env = mock_env();
+        let msg = mock_instantiate_msg();
+        instantiate(deps.as_mut(), env.clone(), mock_info("creator", &[]), msg).unwrap();
+        earn_tokens_into_pool(deps.as_mut(), Addr::unchecked("alice"), Uint128::new(1000)).unwrap();
+
+        // Settled maturities left 100 of interest, the same shares are worth 10% more
+        let mut pool = crate::state::EARN_POOL.load(deps.as_ref().storage).unwrap();
+        pool.total_assets += Uint128::new(100);
+        crate::state::EARN_POOL.save(deps.as_mut().storage, &pool).unwrap();
+
+        let pool: EarnPoolResponse = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::EarnPool {}).unwrap()).unwrap();
+        assert_eq!(pool, EarnPoolResponse { total_assets: Uint128::new(1100), total_shares: Uint128::new(1000) });
+
+        let balance: EarnBalanceResponse = from_binary(
+            &query(deps.as_ref(), env.clone(), QueryMsg::EarnBalance { address: "alice".to_string() }).unwrap(),
+        ).unwrap();
+        assert_eq!(balance, EarnBalanceResponse { shares: Uint128::new(1000), assets: Uint128::new(1100) });
+
+        let to_shares: EarnConversionResponse = from_binary(
+            &query(deps.as_ref(), env.clone(), QueryMsg::ConvertToShares { assets: Uint128::new(550) }).unwrap(),
+        ).unwrap();
+        assert_eq!(to_shares.shares, Uint128::new(500));
+
+        let to_assets: EarnConversionResponse = from_binary(
+            &query(deps.as_ref(), env, QueryMsg::ConvertToAssets { shares: Uint128::new(500) }).unwrap(),
+        ).unwrap();
+        assert_eq!(to_assets.assets, Uint128::new(550));
+    }
+
+    #[test]
+    fn rates_query_follows_utilization() {
+        let mut deps = mock_dependencies();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), mock_instantiate_msg()).unwrap();
+        let maturity_date = mock_env().block.time.seconds() + 60;
+        let msg = ExecuteMsg::CreateMaturity { maturity_date };
+        execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        lend_to_pool(deps.as_mut(), mock_env(), Addr::unchecked("lender"), Uint128::new(1000), maturity_date).unwrap();
+        post_collateral(deps.as_mut(), "borrower", 800);
+        borrow_from_pool(deps.as_mut(), mock_env(), Addr::unchecked("borrower"), Uint128::new(400), maturity_date).unwrap();
+
+        // Half way to the kink adds half of slope1, lenders share it less the 10% reserve factor
+        let rates: RatesResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Rates {}).unwrap()).unwrap();
+        assert_eq!(rates.utilization, Decimal::percent(40));
+        assert_eq!(rates.borrow_rate, Decimal::percent(12));
+        assert_eq!(rates.supply_rate, Decimal::from_ratio(432u128, 10_000u128));
+
+        // A year later the indices have grown by those rates
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let rates: RatesResponse = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::Rates {}).unwrap()).unwrap();
+        assert_eq!(rates.borrow_index, Decimal256::percent(112));
+        assert_eq!(rates.supply_index, Decimal256::from_ratio(10_432u128, 10_000u128));
+
+        // The position entered at index one and its principal grew with the supply index
+        let msg = QueryMsg::LendToPool { address: "lender".to_string() };
+        let lender: LenderPoolResponse = from_binary(&query(deps.as_ref(), env, msg).unwrap()).unwrap();
+        assert_eq!(lender.positions[0].supply_index, Decimal256::one());
+        assert_eq!(lender.positions[0].supply_value, Uint128::new(1043));
+    }
+
+    #[test]
+    fn rates_query_supply_rate() {
+        let mut deps = mock_dependencies();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), mock_instantiate_msg()).unwrap();
+        let maturity_date = mock_env().block.time.seconds() + 60;
+        let msg = ExecuteMsg::CreateMaturity { maturity_date };
+        execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+        lend_to_pool(deps.as_mut(), mock_env(), Addr::unchecked("lender"), Uint128::new(1000), maturity_date).unwrap();
+        post_collateral(deps.as_mut(), "borrower", 2000);
+
+        // Nothing borrowed, nothing to share with lenders
+        let rates: RatesResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Rates {}).unwrap()).unwrap();
+        assert_eq!(rates.borrow_rate, Decimal::percent(10));
+        assert_eq!(rates.supply_rate, Decimal::zero());
+
+        // At the kink 14% on the 80% borrowed, less the 10% reserve factor
+        borrow_from_pool(deps.as_mut(), mock_env(), Addr::unchecked("borrower"), Uint128::new(800), maturity_date).unwrap();
+        let rates: RatesResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Rates {}).unwrap()).unwrap();
+        assert_eq!(rates.utilization, Decimal::percent(80));
+        assert_eq!(rates.borrow_rate, Decimal::percent(14));
+        assert_eq!(rates.supply_rate, Decimal::from_ratio(1008u128, 10_000u128));
+
+        // Fully borrowed, 74% on everything less the reserve factor
+        borrow_from_pool(deps.as_mut(), mock_env(), Addr::unchecked("borrower"), Uint128::new(200), maturity_date).unwrap();
+        let rates: RatesResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Rates {}).unwrap()).unwrap();
+        assert_eq!(rates.utilization, Decimal::one());
+        assert_eq!(rates.borrow_rate, Decimal::percent(74));
+        assert_eq!(rates.supply_rate, Decimal::from_ratio(666u128, 1_000u128));
+    }
+
+    #[test]
+    fn health_factor_query() {
+        let mut deps = mock_dependencies();
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(0);
+        instantiate(deps.as_mut(), env.clone(), mock_info("creator", &[]), mock_instantiate_msg()).unwrap();
+        let query_health = |deps: Deps, env: Env| -> HealthFactorResponse {
+            from_binary(&query(deps, env, QueryMsg::HealthFactor { address: "borrower".to_string() }).unwrap()).unwrap()
+        };
+
+        post_collateral(deps.as_mut(), "borrower", 1000);
+        let health = query_health(deps.as_ref(), env.clone());
+        assert_eq!(health.collateral_value, Uint128::new(1000));
+        assert_eq!(health.remaining_borrow_capacity, Uint128::new(500));
+        assert_eq!(health.health_factor, None);
+
+        let maturity_date = SECONDS_PER_YEAR;
+        execute(deps.as_mut(), env.clone(), mock_info("creator", &[]), ExecuteMsg::CreateMaturity { maturity_date }).unwrap();
+        lend_to_pool(deps.as_mut(), env.clone(), Addr::unchecked("lender"), Uint128::new(1000), maturity_date).unwrap();
+        let msg = ExecuteMsg::BorrowFromPool { amount: Uint128::new(400), maturity_date, quote: None };
+        execute(deps.as_mut(), env.clone(), mock_info("borrower", &[]), msg).unwrap();
+
+        // 80% of the collateral over the debt
+        let health = query_health(deps.as_ref(), env.clone());
+        assert_eq!(health.debt_value, Uint128::new(400));
+        assert_eq!(health.remaining_borrow_capacity, Uint128::new(100));
+        assert_eq!(health.health_factor, Some(Decimal::percent(200)));
+
+        // Borrowing past the max LTV is refused
+        let msg = ExecuteMsg::BorrowFromPool { amount: Uint128::new(101), maturity_date, quote: None };
+        let err = execute(deps.as_mut(), env.clone(), mock_info("borrower", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::Undercollateralized { .. }));
+
+        // The debt keeps accruing, a year at 12% leaves less room
+        env.block.time = Timestamp::from_seconds(SECONDS_PER_YEAR);
+        let health = query_health(deps.as_ref(), env);
+        assert_eq!(health.debt_value, Uint128::new(448));
+        assert_eq!(health.remaining_borrow_capacity, Uint128::new(52));
+    }
+
+    #[test]
+    fn maturities_are_created_listed_and_settled() {
+        let mut deps = mock_dependencies();
+        instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), mock_instantiate_msg()).unwrap();
+        let now = mock_env().block.time.seconds();
+
+        // Only the owner opens maturities, and only future ones
+        let msg = ExecuteMsg::CreateMaturity { maturity_date: now + 60 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg.clone()).unwrap();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityExists { .. }));
+        let msg = ExecuteMsg::CreateMaturity { maturity_date: now };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityClosed { .. }));
+        let msg = ExecuteMsg::CreateMaturity { maturity_date: now + SECONDS_PER_YEAR };
+        execute(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+        // Liquidity stays within the maturity it was lent to
+        post_collateral(deps.as_mut(), "borrower", 1000);
+        lend_to_pool(deps.as_mut(), mock_env(), Addr::unchecked("lender"), Uint128::new(1000), now + 60).unwrap();
+        let msg = ExecuteMsg::BorrowFromPool { amount: Uint128::new(100), maturity_date: now + SECONDS_PER_YEAR, quote: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("borrower", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficientFunds {}));
+        let msg = ExecuteMsg::BorrowFromPool { amount: Uint128::new(250), maturity_date: now + 60, quote: None };
+        execute(deps.as_mut(), mock_env(), mock_info("borrower", &[]), msg).unwrap();
+
+        let res: MaturitiesResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::Maturities {}).unwrap()).unwrap();
+        assert_eq!(res.maturities.len(), 2);
+        assert_eq!(res.maturities[0].maturity_date, now + 60);
+        assert_eq!(res.maturities[0].total_tokens, Uint128::new(750));
+        assert_eq!(res.maturities[0].total_borrowed, Uint128::new(250));
+        assert_eq!(res.maturities[0].total_owed, Uint128::new(1000));
+        assert_eq!(res.maturities[0].utilization, Decimal::percent(25));
+        assert_eq!(res.maturities[1].total_tokens, Uint128::zero());
+
+        // Settling waits for the maturity and reports what it still owes its lenders
+        let msg = ExecuteMsg::SettleMaturity { maturity_date: now + 60 };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), msg.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityNotExpired { .. }));
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(60);
+        let res = execute(deps.as_mut(), env.clone(), mock_info("anyone", &[]), msg.clone()).unwrap();
+        assert!(res.attributes.iter().any(|attr| attr.key == "shortfall" && attr.value == "250"));
+        let err = execute(deps.as_mut(), env.clone(), mock_info("anyone", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityClosed { .. }));
+
+        // Settled maturities drop out of the listing and take no new positions
+        let res: MaturitiesResponse = from_binary(&query(deps.as_ref(), env.clone(), QueryMsg::Maturities {}).unwrap()).unwrap();
+        assert_eq!(res.maturities.len(), 1);
+        assert_eq!(res.maturities[0].maturity_date, now + SECONDS_PER_YEAR);
+        let err = lend_to_pool(deps.as_mut(), env, Addr::unchecked("lender"), Uint128::new(10), now + 60).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityClosed { .. }));
     }
 
 }
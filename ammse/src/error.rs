@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{Decimal, StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -9,21 +9,117 @@ pub enum ContractError {
     #[error("Unauthorized")]
     Unauthorized {},
 
-    #[error("User has existing escrow")]
-    ExistingEscrow {},
+    #[error("Missing role: {role}")]
+    MissingRole { role: String },
+
+    #[error("Maturity {maturity_date} not found")]
+    MaturityNotFound { maturity_date: u64 },
+
+    #[error("Maturity {maturity_date} already exists")]
+    MaturityExists { maturity_date: u64 },
+
+    #[error("Maturity {maturity_date} no longer accepts lends or borrows")]
+    MaturityClosed { maturity_date: u64 },
+
+    #[error("Maturity {maturity_date} has not expired yet")]
+    MaturityNotExpired { maturity_date: u64 },
+
+    #[error("Maturity {maturity_date} cannot pay all its lenders, it has to be settled first")]
+    MaturityNotSettled { maturity_date: u64 },
+
+    #[error("Cannot migrate: {reason}")]
+    InvalidMigration { reason: String },
+
+    #[error("Paused: {action}")]
+    Paused { action: String },
 
     #[error("User has no existing escrow")]
     NoExistingEscrow {},
 
+    #[error("Lender position not found")]
+    NoLenderPosition {},
+
+    #[error("Lender position {position_id} has already been paid out")]
+    PositionSettled { position_id: u64 },
+
+    #[error("Lender position {position_id} has nothing to withdraw until its maturity receives more repayments")]
+    NothingToWithdraw { position_id: u64 },
+
+    #[error("User has no earn deposit")]
+    NoEarnDeposit {},
+
+    #[error("Loan not found")]
+    LoanNotFound {},
+
+    #[error("Loan is not open")]
+    LoanNotOpen {},
+
     #[error("Escrow has not expired")]
     NotExpired {},
 
+    #[error("Token {token} is not accepted by the pool")]
+    UnacceptedToken { token: String },
+
     #[error("Insufficent Funds")]
     InsufficientFunds{},
 
+    #[error("Loan is healthy and not overdue")]
+    NotLiquidatable {},
+
+    #[error("Oracle price of {asset} must not be zero")]
+    InvalidPrice { asset: String },
+
+    #[error("Oracle price of {asset} is stale, last updated at {updated_at}")]
+    StalePrice { asset: String, updated_at: u64 },
+
+    #[error("Oracle price of {asset} claims to be updated at {updated_at}, after the current block")]
+    FuturePrice { asset: String, updated_at: u64 },
+
+    #[error("Oracle price of {asset} at {price} deviates too far from its average of {ema_price}")]
+    PriceDeviation { asset: String, price: Decimal, ema_price: Decimal },
+
+    #[error("No backend key is configured to verify quotes")]
+    NoBackendKey {},
+
+    #[error("Quote signature is invalid")]
+    InvalidQuoteSignature {},
+
+    #[error("Quote expired at {expires_at}")]
+    QuoteExpired { expires_at: u64 },
+
+    #[error("Quote nonce {nonce} was already used")]
+    QuoteNonceUsed { nonce: u64 },
+
+    #[error("Quote does not match the trade")]
+    QuoteMismatch {},
+
+    #[error("Quote was made for another contract or chain")]
+    QuoteWrongDeployment {},
+
+    #[error("Undercollateralized: debt of {debt} exceeds borrow capacity of {borrow_capacity}")]
+    Undercollateralized { debt: Uint128, borrow_capacity: Uint128 },
+
+    #[error("Insufficient backing: {available} available for {required}")]
+    InsufficientBacking { available: Uint128, required: Uint128 },
+
     #[error("Duration Not Met")]
     DurationNotMet{},
     
+    #[error("Invalid config: {reason}")]
+    InvalidConfig { reason: String },
+
+    #[error("No ownership proposal")]
+    NoOwnershipProposal {},
+
+    #[error("Ownership proposal has expired")]
+    OwnershipProposalExpired {},
+
+    #[error("Duration {duration} is outside the allowed range")]
+    InvalidDuration { duration: u64 },
+
+    #[error("Borrow amount exceeds the limit of {limit}")]
+    BorrowLimitExceeded { limit: Uint128 },
+
     #[error("Custom error: {info}")]
     CustomError {
         info: String,
@@ -1,8 +1,13 @@
-use cosmwasm_std::{to_binary, Addr, CosmosMsg, DepsMut, Env, Response, Uint128, WasmMsg, BankMsg, Coin, attr};
-use cw20::Cw20ExecuteMsg;
+use cosmwasm_std::{to_binary, Addr, Binary, CosmosMsg, Decimal, Decimal256, Deps, DepsMut, Empty, Env, Event, Order, Response, StdError, StdResult, Storage, Uint128, WasmMsg};
+use cw20::{AllowanceResponse, Cw20ExecuteMsg, Cw20QueryMsg};
 
 use crate::error::ContractError;
-use crate::state::{ESCROW, VAULT, LENDERS, CONFIG, Escrow, LenderInfo, EARNINGS, BorrowerInfo, BORROWERS, Vault};
+use crate::health::account_health;
+use crate::oracle::load_collateral_asset;
+use crate::quote::{verify_quote, QuoteAction, SignedQuote};
+use crate::interest::{term_payout, utilization};
+use crate::msg::{ConfigUpdate, Cw20HookMsg, TradeBacking};
+use crate::state::{ESCROW, VAULT, COLLATERALS, COLLATERAL_ASSETS, CollateralAsset, ORACLE, OracleConfig, INTEREST_INDEX, MATURITIES, InterestIndex, MaturityBucket, LENDERS, CONFIG, OWNERSHIP_PROPOSAL, PAUSE_STATE, ROLES, COLLECTED_FEES, Config, OwnershipProposal, PausableAction, Role, TermRate, Escrow, LenderInfo, EARN_POOL, EARN_SHARES, BorrowerInfo, BORROWERS, LoanStatus, next_escrow_id, next_lender_position_id, next_loan_id};
 
 pub fn execute_escrow(
     deps: DepsMut,
@@ -12,245 +17,1245 @@ pub fn execute_escrow(
     amount: Uint128,
     time: u64,
 ) -> Result<Response, ContractError> {
-    // let config = CONFIG.load(deps.storage)?;
-    // if config.token != token {
-    //     return Err(ContractError::Unauthorized {});
-    // }
-
-    if ESCROW.may_load(deps.storage)?.is_some() {
-        return Err(ContractError::ExistingEscrow {});
-    }
+    assert_not_paused(deps.storage, PausableAction::Escrow)?;
+    assert_accepted_token(deps.storage, &token, &Cw20HookMsg::Escrow { time })?;
 
+    let escrow_id = next_escrow_id(deps.storage)?;
     let escrow: Escrow = Escrow {
         user: user.clone(),
         amount,
         time: env.block.time.seconds() + time,
     };
 
-    ESCROW.save(deps.storage, &escrow)?;
+    ESCROW.save(deps.storage, (&user, escrow_id), &escrow)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "escrow")
+        .add_attribute("escrow_id", escrow_id.to_string()))
+}
+
+// Checks that configured rates, fees and limits are sane
+pub fn validate_config(config: &Config) -> Result<(), ContractError> {
+    if config.base_interest_rate > Decimal::one() {
+        return Err(ContractError::InvalidConfig { reason: "base_interest_rate must not exceed 100%".to_string() });
+    }
+    if config.optimal_utilization.is_zero() || config.optimal_utilization > Decimal::one() {
+        return Err(ContractError::InvalidConfig { reason: "optimal_utilization must be above 0% and at most 100%".to_string() });
+    }
+    if config.fee_percentage > Decimal::one() {
+        return Err(ContractError::InvalidConfig { reason: "fee_percentage must not exceed 100%".to_string() });
+    }
+    if config.close_factor.is_zero() || config.close_factor > Decimal::one() {
+        return Err(ContractError::InvalidConfig { reason: "close_factor must be above 0% and at most 100%".to_string() });
+    }
+    if config.liquidation_bonus > Decimal::one() {
+        return Err(ContractError::InvalidConfig { reason: "liquidation_bonus must not exceed 100%".to_string() });
+    }
+    // Compressed or uncompressed SEC1 encoding
+    if config.backend_pubkey.as_ref().is_some_and(|key| key.len() != 33 && key.len() != 65) {
+        return Err(ContractError::InvalidConfig { reason: "backend_pubkey must be a 33 or 65 byte secp256k1 key".to_string() });
+    }
+    if config.max_duration == 0 || config.min_duration > config.max_duration {
+        return Err(ContractError::InvalidConfig { reason: "min_duration must not exceed a non-zero max_duration".to_string() });
+    }
+    // Every allowed duration needs a term rate, so the first tier starts at or below min_duration
+    match config.term_rates.first() {
+        Some(first) if first.duration <= config.min_duration => {}
+        _ => return Err(ContractError::InvalidConfig { reason: "term_rates must cover min_duration".to_string() }),
+    }
+    if config.term_rates.windows(2).any(|pair| pair[0].duration >= pair[1].duration) {
+        return Err(ContractError::InvalidConfig { reason: "term_rates must be sorted by ascending duration".to_string() });
+    }
+    if config.term_rates.iter().any(|tier| tier.rate > Decimal::one()) {
+        return Err(ContractError::InvalidConfig { reason: "term rates must not exceed 100%".to_string() });
+    }
+    Ok(())
+}
+
+pub fn assert_duration(config: &Config, duration: u64) -> Result<(), ContractError> {
+    if duration < config.min_duration || duration > config.max_duration {
+        return Err(ContractError::InvalidDuration { duration });
+    }
+    Ok(())
+}
+
+pub fn assert_owner(storage: &dyn Storage, sender: &Addr) -> Result<Config, ContractError> {
+    let config = CONFIG.load(storage)?;
+    if config.owner != *sender {
+        return Err(ContractError::Unauthorized {});
+    }
+    Ok(config)
+}
+
+// Owner updates rates, fees and limits, every changed value is reported with its old and new value
+pub fn update_config(deps: DepsMut, sender: Addr, update: ConfigUpdate) -> Result<Response, ContractError> {
+    let mut config = assert_owner(deps.storage, &sender)?;
+    let mut response = Response::new().add_attribute("action", "update_config");
+
+    fn changed<T: ToString + PartialEq>(response: Response, key: &str, old: &mut T, new: Option<T>) -> Response {
+        match new {
+            Some(new) if new != *old => {
+                let response = response
+                    .add_attribute(format!("old_{}", key), old.to_string())
+                    .add_attribute(format!("new_{}", key), new.to_string());
+                *old = new;
+                response
+            }
+            _ => response,
+        }
+    }
+    response = changed(response, "base_interest_rate", &mut config.base_interest_rate, update.base_interest_rate);
+    response = changed(response, "slope1", &mut config.slope1, update.slope1);
+    response = changed(response, "slope2", &mut config.slope2, update.slope2);
+    response = changed(response, "optimal_utilization", &mut config.optimal_utilization, update.optimal_utilization);
+    response = changed(response, "fee_percentage", &mut config.fee_percentage, update.fee_percentage);
+    response = changed(response, "min_duration", &mut config.min_duration, update.min_duration);
+    response = changed(response, "max_duration", &mut config.max_duration, update.max_duration);
+    response = changed(response, "max_borrow_amount", &mut config.max_borrow_amount, update.max_borrow_amount);
+    response = changed(response, "close_factor", &mut config.close_factor, update.close_factor);
+    response = changed(response, "close_factor_dust", &mut config.close_factor_dust, update.close_factor_dust);
+    response = changed(response, "liquidation_bonus", &mut config.liquidation_bonus, update.liquidation_bonus);
+    if let Some(term_rates) = update.term_rates.filter(|term_rates| *term_rates != config.term_rates) {
+        let format = |term_rates: &[TermRate]| {
+            term_rates.iter().map(|tier| format!("{}:{}", tier.duration, tier.rate)).collect::<Vec<_>>().join(",")
+        };
+        response = response
+            .add_attribute("old_term_rates", format(&config.term_rates))
+            .add_attribute("new_term_rates", format(&term_rates));
+        config.term_rates = term_rates;
+    }
+
+    validate_config(&config)?;
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(response)
+}
+
+// First step of an ownership transfer, replaces any pending proposal
+pub fn propose_new_owner(
+    deps: DepsMut,
+    env: Env,
+    sender: Addr,
+    new_owner: String,
+    expires_in: u64,
+) -> Result<Response, ContractError> {
+    let config = assert_owner(deps.storage, &sender)?;
+    let proposal = OwnershipProposal {
+        new_owner: deps.api.addr_validate(&new_owner)?,
+        expires_at: env.block.time.seconds() + expires_in,
+    };
+    OWNERSHIP_PROPOSAL.save(deps.storage, &proposal)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "propose_new_owner")
+        .add_attribute("old_owner", config.owner)
+        .add_attribute("new_owner", proposal.new_owner)
+        .add_attribute("expires_at", proposal.expires_at.to_string()))
+}
+
+pub fn cancel_ownership_proposal(deps: DepsMut, sender: Addr) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    let proposal = OWNERSHIP_PROPOSAL
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoOwnershipProposal {})?;
+    OWNERSHIP_PROPOSAL.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "cancel_ownership_proposal")
+        .add_attribute("cancelled_owner", proposal.new_owner))
+}
+
+// Second step of an ownership transfer, only the proposed owner can complete it
+pub fn accept_ownership(deps: DepsMut, env: Env, sender: Addr) -> Result<Response, ContractError> {
+    let proposal = OWNERSHIP_PROPOSAL
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoOwnershipProposal {})?;
+    if proposal.new_owner != sender {
+        return Err(ContractError::Unauthorized {});
+    }
+    if env.block.time.seconds() >= proposal.expires_at {
+        return Err(ContractError::OwnershipProposalExpired {});
+    }
+
+    let mut config = CONFIG.load(deps.storage)?;
+    let old_owner = std::mem::replace(&mut config.owner, sender);
+    CONFIG.save(deps.storage, &config)?;
+    OWNERSHIP_PROPOSAL.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "accept_ownership")
+        .add_attribute("old_owner", old_owner)
+        .add_attribute("new_owner", config.owner))
+}
+
+pub fn assert_role(storage: &dyn Storage, role: Role, sender: &Addr) -> Result<(), ContractError> {
+    if !ROLES.has(storage, (role.as_str(), sender)) {
+        return Err(ContractError::MissingRole { role: role.as_str().to_string() });
+    }
+    Ok(())
+}
+
+pub fn grant_role(deps: DepsMut, sender: Addr, role: Role, address: String) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    let address = deps.api.addr_validate(&address)?;
+    ROLES.save(deps.storage, (role.as_str(), &address), &Empty {})?;
+
+    Ok(Response::new()
+        .add_attribute("action", "grant_role")
+        .add_attribute("role", role.as_str())
+        .add_attribute("address", address))
+}
+
+pub fn revoke_role(deps: DepsMut, sender: Addr, role: Role, address: String) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    let address = deps.api.addr_validate(&address)?;
+    ROLES.remove(deps.storage, (role.as_str(), &address));
+
+    Ok(Response::new()
+        .add_attribute("action", "revoke_role")
+        .add_attribute("role", role.as_str())
+        .add_attribute("address", address))
+}
+
+pub fn assert_not_paused(storage: &dyn Storage, action: PausableAction) -> Result<(), ContractError> {
+    let pause = PAUSE_STATE.may_load(storage)?.unwrap_or_default();
+    if pause.is_paused(action) {
+        return Err(ContractError::Paused { action: action.as_str().to_string() });
+    }
+    Ok(())
+}
+
+// Any guardian can stop the contract, only the owner can restart it
+pub fn pause(deps: DepsMut, sender: Addr, action: Option<PausableAction>) -> Result<Response, ContractError> {
+    assert_role(deps.storage, Role::Guardian, &sender)?;
+    set_paused(deps, action, true)
+}
+
+pub fn unpause(deps: DepsMut, sender: Addr, action: Option<PausableAction>) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    set_paused(deps, action, false)
+}
+
+fn set_paused(deps: DepsMut, action: Option<PausableAction>, paused: bool) -> Result<Response, ContractError> {
+    let mut pause = PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default();
+    pause.set(action, paused);
+    PAUSE_STATE.save(deps.storage, &pause)?;
 
-    Ok(Response::new().add_attribute("action", "escrow"))
+    Ok(Response::new()
+        .add_attribute("action", if paused { "pause" } else { "unpause" })
+        .add_attribute("target", action.map_or("global", |action| action.as_str())))
 }
 
-pub fn execute_redeem(deps: DepsMut, env: Env, user: Addr) -> Result<Response, ContractError> {
+// Fee collector withdraws the protocol's share of repaid interest
+pub fn withdraw_fees(deps: DepsMut, sender: Addr, amount: Option<Uint128>) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Withdraw)?;
+    assert_role(deps.storage, Role::FeeCollector, &sender)?;
     let config = CONFIG.load(deps.storage)?;
 
-    let escrow = ESCROW.may_load(deps.storage)?;
-    if escrow.is_none() {
-        return Err(ContractError::NoExistingEscrow {});
+    let collected = COLLECTED_FEES.may_load(deps.storage)?.unwrap_or_default();
+    let amount = amount.unwrap_or(collected);
+    if amount.is_zero() || amount > collected {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    COLLECTED_FEES.save(deps.storage, &(collected - amount))?;
+
+    Ok(Response::new()
+        .add_message(transfer_cw20(&config.token, &sender, amount)?)
+        .add_attribute("action", "withdraw_fees")
+        .add_attribute("amount", amount))
+}
+
+// Cw20 contracts accepted for each hook, the pool token for the pool's own hooks and the registered
+// collateral assets for collateral. Any other contract could claim deposits it never made
+pub fn assert_accepted_token(storage: &dyn Storage, token: &Addr, hook: &Cw20HookMsg) -> Result<(), ContractError> {
+    let accepted = match hook {
+        Cw20HookMsg::Collateral {} => COLLATERAL_ASSETS.has(storage, token),
+        _ => CONFIG.load(storage)?.token == *token,
+    };
+    if !accepted {
+        return Err(ContractError::UnacceptedToken { token: token.to_string() });
     }
+    Ok(())
+}
+
+pub fn execute_redeem(deps: DepsMut, env: Env, user: Addr, escrow_id: u64) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Withdraw)?;
+    let config = CONFIG.load(deps.storage)?;
 
-    let escrow = escrow.unwrap();
+    let escrow = ESCROW
+        .may_load(deps.storage, (&user, escrow_id))?
+        .ok_or(ContractError::NoExistingEscrow {})?;
     if escrow.time > env.block.time.seconds() {
         return Err(ContractError::NotExpired {});
     }
 
-    let msg = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: config.token.to_string(),
-        msg: to_binary(&Cw20ExecuteMsg::Transfer {
-            recipient: user.to_string(),
-            amount: escrow.amount,
-        })?,
-        funds: vec![],
-    });
+    let msg = transfer_cw20(&config.token, &user, escrow.amount)?;
 
-    ESCROW.remove(deps.storage);
+    ESCROW.remove(deps.storage, (&user, escrow_id));
 
     Ok(Response::new()
         .add_message(msg)
-        .add_attribute("action", "redeem"))
+        .add_attribute("action", "redeem")
+        .add_attribute("escrow_id", escrow_id.to_string()))
+}
+
+
+// Owner opens a new maturity that lenders and borrowers can target until it expires
+pub fn create_maturity(deps: DepsMut, env: Env, sender: Addr, maturity_date: u64) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    if maturity_date <= env.block.time.seconds() {
+        return Err(ContractError::MaturityClosed { maturity_date });
+    }
+    if MATURITIES.has(deps.storage, maturity_date) {
+        return Err(ContractError::MaturityExists { maturity_date });
+    }
+    MATURITIES.save(deps.storage, maturity_date, &MaturityBucket::new(maturity_date))?;
+
+    Ok(Response::new()
+        .add_attribute("action", "create_maturity")
+        .add_attribute("maturity_date", maturity_date.to_string()))
+}
+
+// Closes an expired maturity, reporting whether its liquidity covers what its lenders are owed
+pub fn settle_maturity(deps: DepsMut, env: Env, maturity_date: u64) -> Result<Response, ContractError> {
+    // Settling moves the surplus and opens the bucket to withdrawals, it waits out a pause
+    assert_not_paused(deps.storage, PausableAction::Withdraw)?;
+    let mut bucket = load_maturity(deps.storage, maturity_date)?;
+    if bucket.settled {
+        return Err(ContractError::MaturityClosed { maturity_date });
+    }
+    if env.block.time.seconds() < maturity_date {
+        return Err(ContractError::MaturityNotExpired { maturity_date });
+    }
+    bucket.settled = true;
+    let shortfall = bucket.outstanding().saturating_sub(bucket.liquidity.total_tokens);
+    let surplus = sweep_surplus(deps.storage, &mut bucket)?;
+    MATURITIES.save(deps.storage, maturity_date, &bucket)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "settle_maturity")
+        .add_attribute("maturity_date", maturity_date.to_string())
+        .add_attribute("total_tokens", bucket.liquidity.total_tokens)
+        .add_attribute("total_borrowed", bucket.liquidity.total_borrowed)
+        .add_attribute("total_principal", bucket.total_principal)
+        .add_attribute("total_owed", bucket.total_owed)
+        .add_attribute("total_paid", bucket.total_paid)
+        .add_attribute("shortfall", shortfall)
+        .add_attribute("surplus", surplus))
+}
+
+fn load_maturity(storage: &dyn Storage, maturity_date: u64) -> Result<MaturityBucket, ContractError> {
+    MATURITIES
+        .may_load(storage, maturity_date)?
+        .ok_or(ContractError::MaturityNotFound { maturity_date })
+}
+
+// Once a settled maturity holds more than its lenders are still owed, the rest is interest nobody
+// has a claim on. It is the earn pool's yield, or a fee while nobody holds earn shares
+fn sweep_surplus(storage: &mut dyn Storage, bucket: &mut MaturityBucket) -> StdResult<Uint128> {
+    let outstanding = bucket.outstanding();
+    if !bucket.settled || bucket.liquidity.total_tokens <= outstanding {
+        return Ok(Uint128::zero());
+    }
+    let surplus = bucket.liquidity.total_tokens - outstanding;
+    bucket.liquidity.total_tokens = outstanding;
+
+    let mut vault = VAULT.load(storage)?;
+    vault.total_tokens = vault.total_tokens.checked_sub(surplus)?;
+    VAULT.save(storage, &vault)?;
+
+    let mut pool = EARN_POOL.may_load(storage)?.unwrap_or_default();
+    if pool.total_shares.is_zero() {
+        let collected = COLLECTED_FEES.may_load(storage)?.unwrap_or_default();
+        COLLECTED_FEES.save(storage, &(collected + surplus))?;
+    } else {
+        pool.total_assets += surplus;
+        EARN_POOL.save(storage, &pool)?;
+    }
+    Ok(surplus)
 }
 
+// A maturity new positions can still target, along with the time left until it expires
+fn load_open_maturity(storage: &dyn Storage, maturity_date: u64, now: u64) -> Result<(MaturityBucket, u64), ContractError> {
+    let bucket = load_maturity(storage, maturity_date)?;
+    if bucket.settled || maturity_date <= now {
+        return Err(ContractError::MaturityClosed { maturity_date });
+    }
+    Ok((bucket, maturity_date - now))
+}
 
-// Lender lends tokens to the collective vault
+// Lender lends tokens to one maturity, opening a new position for the lender
 pub fn lend_to_pool(
     deps: DepsMut,
     env: Env,
     lender: Addr,
     amount: Uint128,
-    duration: u64
+    maturity_date: u64
 ) -> Result<Response, ContractError> {
-    let mut vault = match VAULT.load(deps.storage) {
-        Ok(v) => v,
-        Err(_) => Vault::default(), // Use the default if not present in storage
-    };
+    let config = CONFIG.load(deps.storage)?;
+    let (_, duration) = load_open_maturity(deps.storage, maturity_date, env.block.time.seconds())?;
+    let rate = config.term_rate(duration).ok_or(ContractError::InvalidDuration { duration })?;
+    lend_at_rate(deps, env, lender, amount, maturity_date, rate)
+}
+
+// Lends at the rate the backend quoted to the lender
+pub fn lend_with_quote(
+    mut deps: DepsMut,
+    env: Env,
+    lender: Addr,
+    amount: Uint128,
+    maturity_date: u64,
+    quote: SignedQuote,
+) -> Result<Response, ContractError> {
+    let payload = verify_quote(deps.branch(), &env, &quote)?;
+    payload.assert_matches(QuoteAction::Lend, &lender, amount, maturity_date)?;
+    let res = lend_at_rate(deps, env, lender, amount, maturity_date, payload.rate)?;
+    Ok(res.add_attribute("quote_nonce", payload.nonce.to_string()))
+}
+
+// Opens a fixed-rate position, the amount owed at maturity is settled here and never changes
+fn lend_at_rate(
+    deps: DepsMut,
+    env: Env,
+    lender: Addr,
+    amount: Uint128,
+    maturity_date: u64,
+    rate: Decimal,
+) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Lend)?;
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let (mut bucket, duration) = load_open_maturity(deps.storage, maturity_date, now)?;
+    assert_duration(&config, duration)?;
+    let index = accrue_interest(deps.storage, &config, now)?;
+
+    // Use the default if not present in storage
+    let mut vault = VAULT.load(deps.storage).unwrap_or_default();
     
     vault.total_tokens += amount;
     VAULT.save(deps.storage, &vault)?;
 
+    let position_id = next_lender_position_id(deps.storage)?;
     let lender_info = LenderInfo {
         lender: lender.clone(),
         amount_lent: amount,
-        maturity_date: env.block.time.seconds() + duration,
+        start_time: now,
+        maturity_date,
+        rate,
+        amount_at_maturity: term_payout(amount, rate, duration),
+        supply_index: index.supply_index,
+        amount_paid: Uint128::zero(),
+        settled: false,
+    };
+    LENDERS.save(deps.storage, (&lender, position_id), &lender_info)?;
+
+    bucket.liquidity.total_tokens += amount;
+    bucket.total_principal += amount;
+    bucket.total_owed += lender_info.amount_at_maturity;
+    MATURITIES.save(deps.storage, maturity_date, &bucket)?;
+
+    Ok(Response::default()
+        .add_attribute("action", "lend")
+        .add_attribute("position_id", position_id.to_string())
+        .add_attribute("rate", rate.to_string())
+        .add_attribute("amount_at_maturity", lender_info.amount_at_maturity))
+}
+
+// Operator settles a trade matched by the backend, the position is only opened once its tokens are secured
+#[allow(clippy::too_many_arguments)]
+pub fn settle_backend_trade(
+    mut deps: DepsMut,
+    env: Env,
+    operator: Addr,
+    lender: String,
+    amount: Uint128,
+    maturity_date: u64,
+    backing: TradeBacking,
+    quote: Option<SignedQuote>,
+) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Lend)?;
+    assert_role(deps.storage, Role::Operator, &operator)?;
+    let lender = deps.api.addr_validate(&lender)?;
+    let config = CONFIG.load(deps.storage)?;
+    // The operator can not pick a rate, only pass on one the backend signed for this trade
+    let (rate, quote_nonce) = match quote {
+        Some(quote) => {
+            let payload = verify_quote(deps.branch(), &env, &quote)?;
+            payload.assert_matches(QuoteAction::Lend, &lender, amount, maturity_date)?;
+            (payload.rate, Some(payload.nonce))
+        }
+        None => {
+            let duration = maturity_date.saturating_sub(env.block.time.seconds());
+            (config.term_rate(duration).ok_or(ContractError::InvalidDuration { duration })?, None)
+        }
+    };
+
+    let backing_msg = match backing {
+        // The escrowed tokens are already held by the contract, move them into the vault
+        TradeBacking::Escrow { escrow_id } => {
+            let mut escrow = ESCROW
+                .may_load(deps.storage, (&lender, escrow_id))?
+                .ok_or(ContractError::NoExistingEscrow {})?;
+            if escrow.amount < amount {
+                return Err(ContractError::InsufficientBacking { available: escrow.amount, required: amount });
+            }
+            escrow.amount -= amount;
+            if escrow.amount.is_zero() {
+                ESCROW.remove(deps.storage, (&lender, escrow_id));
+            } else {
+                ESCROW.save(deps.storage, (&lender, escrow_id), &escrow)?;
+            }
+            None
+        }
+        // Pull the tokens from the lender, a failing TransferFrom reverts the whole settlement
+        TradeBacking::Allowance {} => {
+            let allowance: AllowanceResponse = deps.querier.query_wasm_smart(
+                &config.token,
+                &Cw20QueryMsg::Allowance {
+                    owner: lender.to_string(),
+                    spender: env.contract.address.to_string(),
+                },
+            )?;
+            if allowance.allowance < amount {
+                return Err(ContractError::InsufficientBacking { available: allowance.allowance, required: amount });
+            }
+            Some(CosmosMsg::Wasm(WasmMsg::Execute {
+                contract_addr: config.token.to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::TransferFrom {
+                    owner: lender.to_string(),
+                    recipient: env.contract.address.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            }))
+        }
     };
-    LENDERS.save(deps.storage, &lender_info)?;
 
-    Ok(Response::default().add_attribute("action", "lend"))
+    let res = lend_at_rate(deps, env, lender, amount, maturity_date, rate)?;
+    Ok(res
+        .add_messages(backing_msg)
+        .add_attributes(quote_nonce.map(|nonce| ("quote_nonce", nonce.to_string())))
+        .add_attribute("settled_by", operator))
 }
 
+// Borrower takes tokens out of one maturity, the borrowed tokens are paid out in CONFIG.token
 pub fn borrow_from_pool(
     deps: DepsMut,
     env: Env,
     borrower: Addr,
     amount: Uint128,
-    duration: u64
+    maturity_date: u64
 ) -> Result<Response, ContractError> {
-    let mut vault = match VAULT.load(deps.storage) {
-        Ok(v) => v,
-        Err(_) => Vault::default(), // Use the default if not present in storage
-    };
-    if vault.total_tokens < amount {
+    borrow_at_rate(deps, env, borrower, amount, maturity_date, None)
+}
+
+// Borrows at the fixed rate the backend quoted to the borrower
+pub fn borrow_with_quote(
+    mut deps: DepsMut,
+    env: Env,
+    borrower: Addr,
+    amount: Uint128,
+    maturity_date: u64,
+    quote: SignedQuote,
+) -> Result<Response, ContractError> {
+    let payload = verify_quote(deps.branch(), &env, &quote)?;
+    payload.assert_matches(QuoteAction::Borrow, &borrower, amount, maturity_date)?;
+    let res = borrow_at_rate(deps, env, borrower, amount, maturity_date, Some(payload.rate))?;
+    Ok(res.add_attribute("quote_nonce", payload.nonce.to_string()))
+}
+
+// Opens a loan that follows the borrow index, or that owes the whole term's interest at `fixed_rate` up front
+fn borrow_at_rate(
+    deps: DepsMut,
+    env: Env,
+    borrower: Addr,
+    amount: Uint128,
+    maturity_date: u64,
+    fixed_rate: Option<Decimal>,
+) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Borrow)?;
+    let config = CONFIG.load(deps.storage)?;
+    let (mut bucket, duration) = load_open_maturity(deps.storage, maturity_date, env.block.time.seconds())?;
+    assert_duration(&config, duration)?;
+    if amount > config.max_borrow_amount {
+        return Err(ContractError::BorrowLimitExceeded { limit: config.max_borrow_amount });
+    }
+    let now = env.block.time.seconds();
+    let index = accrue_interest(deps.storage, &config, now)?;
+
+    // Only the liquidity lent to this maturity can be borrowed from it
+    if bucket.liquidity.total_tokens < amount {
         return Err(ContractError::InsufficientFunds {});
     }
+
+    // The new debt has to stay within the borrower's max LTV
+    let interest = fixed_rate.map(|rate| term_payout(amount, rate, duration) - amount).unwrap_or_default();
+    let mut health = account_health(deps.as_ref(), now, &borrower, index.borrow_index)?;
+    health.debt += amount + interest;
+    if !health.is_within_capacity() {
+        return Err(ContractError::Undercollateralized { debt: health.debt, borrow_capacity: health.borrow_capacity });
+    }
+    bucket.liquidity.total_tokens -= amount;
+    bucket.liquidity.total_borrowed += amount;
+    MATURITIES.save(deps.storage, maturity_date, &bucket)?;
+
+    // Use the default if not present in storage
+    let mut vault = VAULT.load(deps.storage).unwrap_or_default();
     vault.total_tokens -= amount;
+    vault.total_borrowed += amount;
     VAULT.save(deps.storage, &vault)?;
 
+    let loan_id = next_loan_id(deps.storage)?;
     let borrower_info = BorrowerInfo {
         borrower: borrower.clone(),
-        amount_borrowed: amount,
-        maturity_date: env.block.time.seconds() + duration,
+        principal: amount,
+        interest_accrued: interest,
+        amount_repaid: Uint128::zero(),
+        start_time: now,
+        maturity_date,
+        borrow_index: index.borrow_index,
+        fixed_rate,
+        status: LoanStatus::Open,
+    };
+    BORROWERS.save(deps.storage, (&borrower, loan_id), &borrower_info)?;
+
+    Ok(Response::default()
+        .add_message(transfer_cw20(&config.token, &borrower, amount)?)
+        .add_attribute("action", "borrow")
+        .add_attribute("loan_id", loan_id.to_string()))
+}
+
+
+// Brings both interest indices up to `now`, every call that changes the vault's utilization goes through here first
+pub fn accrue_interest(storage: &mut dyn Storage, config: &Config, now: u64) -> StdResult<InterestIndex> {
+    let vault = VAULT.may_load(storage)?.unwrap_or_default();
+    let mut index = INTEREST_INDEX.may_load(storage)?.unwrap_or_else(|| InterestIndex::new(now));
+    index.accrue(config, utilization(&vault), now)?;
+    INTEREST_INDEX.save(storage, &index)?;
+    Ok(index)
+}
+
+// Grows the outstanding debt, interest included, from the loan's index to the current borrow index
+pub fn accrue_loan_interest(loan: &mut BorrowerInfo, borrow_index: Decimal256) -> StdResult<()> {
+    let debt = loan.outstanding_debt();
+    let grown = loan.debt_at(borrow_index)?;
+    loan.interest_accrued += grown - debt;
+    loan.borrow_index = borrow_index;
+    Ok(())
+}
+
+// Borrower repays a loan, the payment covers accrued interest first and then principal
+pub fn repay_loan(
+    deps: DepsMut,
+    env: Env,
+    borrower: Addr,
+    loan_id: u64,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    // Repaying only lowers risk, so it stays open unless everything is paused
+    if PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default().global {
+        return Err(ContractError::Paused { action: "repay".to_string() });
+    }
+    let config = CONFIG.load(deps.storage)?;
+    let mut loan = BORROWERS
+        .may_load(deps.storage, (&borrower, loan_id))?
+        .ok_or(ContractError::LoanNotFound {})?;
+    if !loan.status.is_outstanding() {
+        return Err(ContractError::LoanNotOpen {});
+    }
+
+    // Variable rate, the loan follows the borrow index
+    let index = accrue_interest(deps.storage, &config, env.block.time.seconds())?;
+    accrue_loan_interest(&mut loan, index.borrow_index)?;
+
+    let repayment = apply_repayment(deps.storage, &config, &mut loan, amount)?;
+    BORROWERS.save(deps.storage, (&borrower, loan_id), &loan)?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "repay")
+        .add_attribute("loan_id", loan_id.to_string())
+        .add_attribute("interest_paid", repayment.interest_paid)
+        .add_attribute("principal_paid", repayment.principal_paid)
+        .add_attribute("fee", repayment.fee)
+        .add_attribute("remaining_debt", loan.outstanding_debt());
+
+    // Anything paid above the outstanding debt is refunded to the borrower
+    let refund = amount - repayment.applied();
+    if !refund.is_zero() {
+        response = response.add_message(transfer_cw20(&config.token, &borrower, refund)?);
+    }
+
+    Ok(response)
+}
+
+struct Repayment {
+    interest_paid: Uint128,
+    principal_paid: Uint128,
+    fee: Uint128,
+}
+
+impl Repayment {
+    fn applied(&self) -> Uint128 {
+        self.interest_paid + self.principal_paid
+    }
+}
+
+// Pays down accrued interest first and then principal. The protocol keeps its fee on the interest,
+// principal plus the rest goes back into the loan's maturity
+fn apply_repayment(
+    storage: &mut dyn Storage,
+    config: &Config,
+    loan: &mut BorrowerInfo,
+    amount: Uint128,
+) -> Result<Repayment, ContractError> {
+    let interest_paid = amount.min(loan.interest_accrued);
+    loan.interest_accrued -= interest_paid;
+    let principal_paid = (amount - interest_paid).min(loan.principal);
+    loan.principal -= principal_paid;
+
+    let repayment = Repayment { interest_paid, principal_paid, fee: interest_paid * config.fee_percentage };
+    loan.amount_repaid += repayment.applied();
+    if loan.outstanding_debt().is_zero() {
+        loan.status = LoanStatus::Repaid;
+    }
+
+    if !repayment.fee.is_zero() {
+        let collected = COLLECTED_FEES.may_load(storage)?.unwrap_or_default();
+        COLLECTED_FEES.save(storage, &(collected + repayment.fee))?;
+    }
+    let returned = repayment.applied() - repayment.fee;
+    let mut bucket = load_maturity(storage, loan.maturity_date)?;
+    bucket.liquidity.total_tokens += returned;
+    bucket.liquidity.total_borrowed = bucket.liquidity.total_borrowed.saturating_sub(principal_paid);
+
+    let mut vault = VAULT.load(storage).unwrap_or_default();
+    vault.total_tokens += returned;
+    vault.total_borrowed = vault.total_borrowed.saturating_sub(principal_paid);
+    VAULT.save(storage, &vault)?;
+
+    // Late repayments into a settled maturity go to whoever is still owed, the rest is yield
+    sweep_surplus(storage, &mut bucket)?;
+    MATURITIES.save(storage, loan.maturity_date, &bucket)?;
+
+    Ok(repayment)
+}
+
+// Anyone repays part of an undercollateralized or overdue loan and receives the borrower's
+// collateral worth the repaid amount plus the liquidation bonus
+pub fn liquidate(
+    deps: DepsMut,
+    env: Env,
+    liquidator: Addr,
+    borrower: String,
+    loan_id: u64,
+    repay_amount: Uint128,
+) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Liquidate)?;
+    let borrower = deps.api.addr_validate(&borrower)?;
+    let config = CONFIG.load(deps.storage)?;
+    let mut loan = BORROWERS
+        .may_load(deps.storage, (&borrower, loan_id))?
+        .ok_or(ContractError::LoanNotFound {})?;
+    if !loan.status.is_outstanding() {
+        return Err(ContractError::LoanNotOpen {});
+    }
+
+    let now = env.block.time.seconds();
+    let index = accrue_interest(deps.storage, &config, now)?;
+    accrue_loan_interest(&mut loan, index.borrow_index)?;
+    let health = account_health(deps.as_ref(), now, &borrower, index.borrow_index)?;
+    let reason = match health.health_factor() {
+        Some(health_factor) if health_factor < Decimal::one() => "undercollateralized",
+        _ if now > loan.maturity_date => "overdue",
+        _ => return Err(ContractError::NotLiquidatable {}),
     };
-    BORROWERS.save(deps.storage, &borrower_info)?;
 
-    Ok(Response::default().add_attribute("action", "borrow"))
+    // The close factor caps how much of the loan a single liquidation repays, a debt down to dust
+    // can be cleared at once
+    let debt = loan.outstanding_debt();
+    let max_repay = if debt <= config.close_factor_dust { debt } else { debt * config.close_factor };
+    // Nor can it repay more than the collateral left covers with the bonus on top
+    let collateral_value = seizable_value(deps.as_ref(), now, &borrower)?;
+    let covered = collateral_value.multiply_ratio(Decimal::one().atomics(), (Decimal::one() + config.liquidation_bonus).atomics());
+    let repay_amount = repay_amount.min(max_repay).min(covered);
+    if repay_amount.is_zero() {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    let repayment = apply_repayment(deps.storage, &config, &mut loan, repay_amount)?;
+    // The borrower let the loan run past its maturity, it stays defaulted even once liquidators cleared it
+    if now > loan.maturity_date {
+        loan.status = LoanStatus::Defaulted;
+    }
+    BORROWERS.save(deps.storage, (&borrower, loan_id), &loan)?;
+
+    // Seize collateral worth the repayment plus the bonus, asset by asset until it is covered
+    let mut owed_value = repay_amount * (Decimal::one() + config.liquidation_bonus);
+    let collaterals = COLLATERALS
+        .prefix(&borrower)
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut seized = vec![];
+    for (asset, balance) in collaterals {
+        if owed_value.is_zero() {
+            break;
+        }
+        let params = match load_collateral_asset(deps.as_ref(), &asset, now)? {
+            Some(params) => params,
+            None => continue,
+        };
+        let amount = owed_value.multiply_ratio(Decimal::one().atomics(), params.price.atomics()).min(balance);
+        if amount.is_zero() {
+            continue;
+        }
+        owed_value = owed_value.saturating_sub(params.value(amount)?);
+        if amount == balance {
+            COLLATERALS.remove(deps.storage, (&borrower, &asset));
+        } else {
+            COLLATERALS.save(deps.storage, (&borrower, &asset), &(balance - amount))?;
+        }
+        seized.push((asset, amount));
+    }
+
+    // A failing TransferFrom reverts the whole liquidation
+    let pull_msg = CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: config.token.to_string(),
+        msg: to_binary(&Cw20ExecuteMsg::TransferFrom {
+            owner: liquidator.to_string(),
+            recipient: env.contract.address.to_string(),
+            amount: repay_amount,
+        })?,
+        funds: vec![],
+    });
+    let mut response = Response::new()
+        .add_message(pull_msg)
+        .add_attribute("action", "liquidate")
+        .add_event(
+            Event::new("liquidation")
+                .add_attribute("liquidator", liquidator.as_str())
+                .add_attribute("borrower", borrower.as_str())
+                .add_attribute("loan_id", loan_id.to_string())
+                .add_attribute("reason", reason)
+                .add_attribute("repaid", repay_amount)
+                .add_attribute("fee", repayment.fee)
+                .add_attribute("remaining_debt", loan.outstanding_debt()),
+        );
+    for (asset, amount) in seized {
+        response = response
+            .add_message(transfer_cw20(&asset, &liquidator, amount)?)
+            .add_event(
+                Event::new("collateral_seized")
+                    .add_attribute("borrower", borrower.as_str())
+                    .add_attribute("asset", asset.as_str())
+                    .add_attribute("amount", amount),
+            );
+    }
+
+    Ok(response)
+}
+
+// Value of the borrower's collateral a liquidation can seize, assets without a price are left out
+fn seizable_value(deps: Deps, now: u64, borrower: &Addr) -> Result<Uint128, ContractError> {
+    let mut total = Uint128::zero();
+    for item in COLLATERALS.prefix(borrower).range(deps.storage, None, None, Order::Ascending) {
+        let (asset, balance) = item?;
+        if let Some(params) = load_collateral_asset(deps, &asset, now)? {
+            total += params.value(balance)?;
+        }
+    }
+    Ok(total)
+}
+
+// Owner registers a cw20 asset as collateral or updates its price and risk parameters
+pub fn set_collateral_asset(
+    deps: DepsMut,
+    sender: Addr,
+    asset: String,
+    params: CollateralAsset,
+) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    let asset = deps.api.addr_validate(&asset)?;
+    if params.price.is_zero() {
+        return Err(ContractError::InvalidConfig { reason: "collateral price must not be zero".to_string() });
+    }
+    // Borrowing up to the max LTV has to leave the health factor above one
+    if params.max_ltv.is_zero() || params.max_ltv >= params.liquidation_threshold || params.liquidation_threshold > Decimal::one() {
+        return Err(ContractError::InvalidConfig {
+            reason: "max_ltv must be above 0% and below a liquidation_threshold of at most 100%".to_string(),
+        });
+    }
+    COLLATERAL_ASSETS.save(deps.storage, &asset, &params)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_collateral_asset")
+        .add_attribute("asset", asset)
+        .add_attribute("price", params.price.to_string())
+        .add_attribute("max_ltv", params.max_ltv.to_string())
+        .add_attribute("liquidation_threshold", params.liquidation_threshold.to_string()))
+}
+
+// Owner points collateral pricing at an oracle, replacing the registered prices
+pub fn set_oracle(deps: DepsMut, sender: Addr, oracle: OracleConfig) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    if oracle.max_price_age == 0 {
+        return Err(ContractError::InvalidConfig { reason: "max_price_age must not be zero".to_string() });
+    }
+    if oracle.max_price_deviation.is_zero() || oracle.max_price_deviation > Decimal::one() {
+        return Err(ContractError::InvalidConfig { reason: "max_price_deviation must be above 0% and at most 100%".to_string() });
+    }
+    ORACLE.save(deps.storage, &oracle)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_oracle")
+        .add_attribute("oracle", oracle.address)
+        .add_attribute("max_price_age", oracle.max_price_age.to_string())
+        .add_attribute("max_price_deviation", oracle.max_price_deviation.to_string()))
+}
+
+// Owner sets or rotates the backend's key, quotes signed with the previous key stop working
+pub fn set_backend_pubkey(deps: DepsMut, sender: Addr, pubkey: Option<Binary>) -> Result<Response, ContractError> {
+    assert_owner(deps.storage, &sender)?;
+    let mut config = CONFIG.load(deps.storage)?;
+    let format = |key: &Option<Binary>| key.as_ref().map_or("none".to_string(), Binary::to_base64);
+    let response = Response::new()
+        .add_attribute("action", "set_backend_pubkey")
+        .add_attribute("old_backend_pubkey", format(&config.backend_pubkey))
+        .add_attribute("new_backend_pubkey", format(&pubkey));
+    config.backend_pubkey = pubkey;
+    validate_config(&config)?;
+    CONFIG.save(deps.storage, &config)?;
+    Ok(response)
+}
+
+// Credits a Cw20 deposit of a registered asset to the borrower's collateral, the receive hook
+// has checked `asset` is registered
+pub fn deposit_collateral(deps: DepsMut, borrower: Addr, asset: Addr, amount: Uint128) -> Result<Response, ContractError> {
+    // Adding collateral only lowers risk, so it stays open unless everything is paused
+    if PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default().global {
+        return Err(ContractError::Paused { action: "deposit_collateral".to_string() });
+    }
+    let balance = COLLATERALS.may_load(deps.storage, (&borrower, &asset))?.unwrap_or_default() + amount;
+    COLLATERALS.save(deps.storage, (&borrower, &asset), &balance)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "deposit_collateral")
+        .add_attribute("asset", asset)
+        .add_attribute("amount", amount)
+        .add_attribute("balance", balance))
 }
 
+// Borrower takes collateral back, as long as what remains still covers their open loans
+pub fn withdraw_collateral(
+    deps: DepsMut,
+    env: Env,
+    borrower: Addr,
+    asset: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    assert_not_paused(deps.storage, PausableAction::Withdraw)?;
+    let asset = deps.api.addr_validate(&asset)?;
+    let balance = COLLATERALS.may_load(deps.storage, (&borrower, &asset))?.unwrap_or_default();
+    if amount.is_zero() || amount > balance {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    let remaining = balance - amount;
+
+    // The collateral left once the withdrawn amount is gone has to stay within the max LTV
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+    let index = accrue_interest(deps.storage, &config, now)?;
+    let mut health = account_health(deps.as_ref(), now, &borrower, index.borrow_index)?;
+    if let Some(params) = load_collateral_asset(deps.as_ref(), &asset, now)? {
+        health.remove_collateral(&params, balance)?;
+        health.add_collateral(&params, remaining)?;
+    }
+    if !health.is_within_capacity() {
+        return Err(ContractError::Undercollateralized { debt: health.debt, borrow_capacity: health.borrow_capacity });
+    }
+
+    if remaining.is_zero() {
+        COLLATERALS.remove(deps.storage, (&borrower, &asset));
+    } else {
+        COLLATERALS.save(deps.storage, (&borrower, &asset), &remaining)?;
+    }
+
+    Ok(Response::new()
+        .add_message(transfer_cw20(&asset, &borrower, amount)?)
+        .add_attribute("action", "withdraw_collateral")
+        .add_attribute("asset", asset)
+        .add_attribute("amount", amount)
+        .add_attribute("balance", remaining))
+}
 
-// Release tokens back to the lender when the duration ends
+// Release tokens back to the lender when the duration of one of their positions ends
 pub fn release_from_pool(
     deps: DepsMut,
     env: Env,
-    lender: Addr
+    lender: Addr,
+    position_id: u64,
 ) -> Result<Response, ContractError> {
-    let lender_info = LENDERS.load(deps.storage)?;
+    assert_not_paused(deps.storage, PausableAction::Withdraw)?;
+    let config = CONFIG.load(deps.storage)?;
+    let mut lender_info = LENDERS
+        .may_load(deps.storage, (&lender, position_id))?
+        .ok_or(ContractError::NoLenderPosition {})?;
+    if lender_info.settled {
+        return Err(ContractError::PositionSettled { position_id });
+    }
 
     if env.block.time.seconds() < lender_info.maturity_date {
         return Err(ContractError::DurationNotMet {});
     }
 
-    let mut vault = VAULT.load(deps.storage)?;
-    if vault.total_tokens < lender_info.amount_lent {
-        return Err(ContractError::InsufficientFunds {});
+    accrue_interest(deps.storage, &config, env.block.time.seconds())?;
+
+    // Lenders are paid from their own maturity, which their borrowers' repayments refill
+    let mut bucket = load_maturity(deps.storage, lender_info.maturity_date)?;
+    // Borrowers can still repay until the maturity is settled, short buckets wait for it
+    if !bucket.settled && bucket.liquidity.total_tokens < bucket.outstanding() {
+        return Err(ContractError::MaturityNotSettled { maturity_date: lender_info.maturity_date });
     }
-    vault.total_tokens -= lender_info.amount_lent;
+    let payout = settlement_share(&bucket, &lender_info)
+        .checked_sub(lender_info.amount_paid)
+        .map_err(StdError::from)?;
+    if payout.is_zero() {
+        return Err(ContractError::NothingToWithdraw { position_id });
+    }
+    bucket.liquidity.total_tokens = bucket.liquidity.total_tokens.checked_sub(payout).map_err(StdError::from)?;
+    bucket.total_paid += payout;
+
+    let mut vault = VAULT.load(deps.storage)?;
+    vault.total_tokens = vault.total_tokens.checked_sub(payout).map_err(StdError::from)?;
     VAULT.save(deps.storage, &vault)?;
 
-    // Remove the lender's information after releasing the tokens
-    LENDERS.remove(deps.storage);
+    // Rounding leaves dust behind once the last lender is paid
+    sweep_surplus(deps.storage, &mut bucket)?;
+    MATURITIES.save(deps.storage, lender_info.maturity_date, &bucket)?;
+
+    // Keep the paid out position as a record, it cannot be withdrawn twice. A position paid only
+    // in part stays open for what later repayments or liquidations bring into the maturity
+    lender_info.amount_paid += payout;
+    lender_info.settled = lender_info.amount_paid >= lender_info.amount_at_maturity;
+    LENDERS.save(deps.storage, (&lender, position_id), &lender_info)?;
+
+    Ok(Response::default()
+        .add_message(transfer_cw20(&config.token, &lender, payout)?)
+        .add_attribute("action", "release")
+        .add_attribute("position_id", position_id.to_string())
+        .add_attribute("amount", payout))
+}
 
-    Ok(Response::default().add_attribute("action", "release"))
+// A lender's share of everything the bucket has had for its lenders, paid out or still held.
+// Principal is covered before interest and every lender of the maturity is entitled to the
+// same fraction of their claim, whatever the order they withdraw in. The share only grows as
+// repayments come in, so it never falls below what the lender was already paid
+fn settlement_share(bucket: &MaturityBucket, lender_info: &LenderInfo) -> Uint128 {
+    let available = bucket.liquidity.total_tokens + bucket.total_paid;
+    if available >= bucket.total_owed {
+        return lender_info.amount_at_maturity;
+    }
+    if available >= bucket.total_principal {
+        let interest = lender_info.amount_at_maturity - lender_info.amount_lent;
+        let interest_owed = bucket.total_owed - bucket.total_principal;
+        return lender_info.amount_lent + interest.multiply_ratio(available - bucket.total_principal, interest_owed);
+    }
+    lender_info.amount_lent.multiply_ratio(available, bucket.total_principal)
 }
 
+// Deposits tokens into the earn pool and mints shares at the current price per share
 pub fn earn_tokens_into_pool(
     deps: DepsMut,
-    _env: Env,  
     user: Addr,
     amount: Uint128,
 ) -> Result<Response, ContractError> {
-    // First, we need to add the user's tokens to the vault.
-    let mut vault = match VAULT.load(deps.storage) {
-        Ok(v) => v,
-        Err(_) => Vault::default(), // Use the default if not present in storage
-    };
-    vault.total_tokens += amount;
-    VAULT.save(deps.storage, &vault)?;
+    assert_not_paused(deps.storage, PausableAction::Earn)?;
+
+    // Mint shares against the earn pool, which holds its own tokens apart from the maturities
+    let mut pool = EARN_POOL.may_load(deps.storage)?.unwrap_or_default();
+    let shares = pool.convert_to_shares(amount);
+    if shares.is_zero() {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    pool.total_assets += amount;
+    pool.total_shares += shares;
+    EARN_POOL.save(deps.storage, &pool)?;
 
-    // Then, record the user's contribution to a different mapping than the LENDERS one, since the logic is a bit different.
-    let mut user_earnings = EARNINGS.load(deps.storage)?;
-    user_earnings.amount_supplied += amount;
-    user_earnings.user = user;
-    EARNINGS.save(deps.storage, &user_earnings)?;
+    EARN_SHARES.update(deps.storage, &user, |balance| -> StdResult<_> {
+        Ok(balance.unwrap_or_default() + shares)
+    })?;
 
-    Ok(Response::default().add_attribute("action", "earn"))
+    Ok(Response::default()
+        .add_attribute("action", "earn")
+        .add_attribute("shares", shares))
 }
 
+// Withdraws part or all of the user's earn claim by burning shares, `None` redeems every share
 pub fn withdraw_from_pool_for_earn(
     deps: DepsMut,
-    _env: Env,
     user: Addr,
+    amount: Option<Uint128>,
 ) -> Result<Response, ContractError> {
-    let user_earnings = EARNINGS.load(deps.storage)?;
-
-    // Here, compute the actual amount the user can withdraw. This can be based on various factors.
-    // TODO:    
-    let amount_to_withdraw = user_earnings.amount_supplied;
-
-    // Ensure the vault has enough funds.
-    let mut vault = VAULT.load(deps.storage)?;
-    if vault.total_tokens < amount_to_withdraw {
+    assert_not_paused(deps.storage, PausableAction::Withdraw)?;
+    let config = CONFIG.load(deps.storage)?;
+    let user_shares = EARN_SHARES
+        .may_load(deps.storage, &user)?
+        .filter(|shares| !shares.is_zero())
+        .ok_or(ContractError::NoEarnDeposit {})?;
+
+    // The claim includes the yield swept in from settled maturities
+    let mut pool = EARN_POOL.load(deps.storage)?;
+    let (amount_to_withdraw, shares_burned) = match amount {
+        Some(assets) => (assets, pool.shares_to_withdraw(assets)?),
+        None => (pool.convert_to_assets(user_shares), user_shares),
+    };
+    if amount_to_withdraw.is_zero() || shares_burned > user_shares {
         return Err(ContractError::InsufficientFunds {});
     }
 
-    // Update the vault's total tokens.
-    vault.total_tokens -= amount_to_withdraw;
-    VAULT.save(deps.storage, &vault)?;
-
-    // Reset the user's earnings to 0.
-    EARNINGS.remove(deps.storage);
+    // The pool only pays out its own tokens, never the lenders'
+    pool.total_assets = pool
+        .total_assets
+        .checked_sub(amount_to_withdraw)
+        .map_err(|_| ContractError::InsufficientFunds {})?;
+    pool.total_shares = pool.total_shares.checked_sub(shares_burned).map_err(StdError::from)?;
+    EARN_POOL.save(deps.storage, &pool)?;
+
+    // Keep what is left of the user's shares, or remove the balance once fully withdrawn.
+    let remaining_shares = user_shares - shares_burned;
+    if remaining_shares.is_zero() {
+        EARN_SHARES.remove(deps.storage, &user);
+    } else {
+        EARN_SHARES.save(deps.storage, &user, &remaining_shares)?;
+    }
 
-    Ok(Response::default().add_attribute("action", "withdraw for earn"))
+    Ok(Response::default()
+        .add_message(transfer_cw20(&config.token, &user, amount_to_withdraw)?)
+        .add_attribute("action", "withdraw for earn")
+        .add_attribute("amount", amount_to_withdraw)
+        .add_attribute("shares", shares_burned))
 }
 
-// this is a helper to move the tokens, so the business logic is easy to read
-fn send_tokens(to_address: Addr, amount: Vec<Coin>, action: &str) -> Response {
-    Response::new()
-        .add_message(BankMsg::Send {
-            to_address: to_address.clone().into(),
+// this is a helper to build a Cw20 transfer out of the contract
+pub fn transfer_cw20(token: &Addr, recipient: &Addr, amount: Uint128) -> StdResult<CosmosMsg> {
+    Ok(CosmosMsg::Wasm(WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        msg: to_binary(&Cw20ExecuteMsg::Transfer {
+            recipient: recipient.to_string(),
             amount,
-        })
-        .add_attribute("action", action)
-        .add_attribute("to", to_address)
+        })?,
+        funds: vec![],
+    }))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::state::{Earnings, Config};
+    use crate::quote::testing::{backend_pubkey, sign_quote};
+    use crate::quote::QuotePayload;
+    use crate::state::{EarnPool, Vault, SECONDS_PER_YEAR};
 
     use super::*;
-    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
-    use cosmwasm_std::{coins, from_binary, Timestamp};
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::{attr, from_binary, Order, Timestamp};
+
+    fn mock_config() -> Config {
+        Config {
+            owner: Addr::unchecked("input"),
+            token: Addr::unchecked("token_address"),
+            base_interest_rate: Decimal::percent(10),
+            // a flat curve keeps the borrow rate at the base rate
+            slope1: Decimal::zero(),
+            slope2: Decimal::zero(),
+            optimal_utilization: Decimal::percent(80),
+            fee_percentage: Decimal::zero(),
+            min_duration: 0,
+            max_duration: SECONDS_PER_YEAR,
+            max_borrow_amount: Uint128::new(1_000_000),
+            close_factor: Decimal::percent(50),
+            close_factor_dust: Uint128::zero(),
+            liquidation_bonus: Decimal::percent(10),
+            backend_pubkey: None,
+            term_rates: vec![TermRate { duration: 0, rate: Decimal::percent(10) }],
+        }
+    }
+
+    // Posts `amount` of a collateral asset priced at par, which backs half its value
+    fn post_collateral(storage: &mut dyn Storage, borrower: &Addr, amount: u128) {
+        let asset = Addr::unchecked("collateral");
+        let params = CollateralAsset {
+            price: Decimal::one(),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        COLLATERAL_ASSETS.save(storage, &asset, &params).unwrap();
+        let balance = COLLATERALS.may_load(storage, (borrower, &asset)).unwrap().unwrap_or_default();
+        COLLATERALS.save(storage, (borrower, &asset), &(balance + Uint128::new(amount))).unwrap();
+    }
+
+    // Opens the maturity `duration` seconds from now with `liquidity` already lent into it
+    fn open_maturity(storage: &mut dyn Storage, env: &Env, duration: u64, liquidity: u128) -> u64 {
+        let maturity_date = env.block.time.seconds() + duration;
+        let mut bucket = MATURITIES
+            .may_load(storage, maturity_date)
+            .unwrap()
+            .unwrap_or_else(|| MaturityBucket::new(maturity_date));
+        bucket.liquidity.total_tokens += Uint128::new(liquidity);
+        MATURITIES.save(storage, maturity_date, &bucket).unwrap();
+        maturity_date
+    }
 
     #[test]
     fn test_execute_escrow() {
         let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         {
             let env = mock_env();
-            let info = mock_info("user_addr", &coins(1000, "token"));
             let user = Addr::unchecked("user_addr");
-            let token = Addr::unchecked("token");
+            let token = Addr::unchecked("token_address");
             let amount = Uint128::new(500);
             let time = 60u64; // 1 minute
 
             // Attempt to execute escrow
             let res = execute_escrow(deps.as_mut(), env, user, token, amount, time);
             
-            assert_eq!(res.unwrap().attributes,vec![attr("action", "escrow")]);
+            assert_eq!(res.unwrap().attributes,vec![attr("action", "escrow"), attr("escrow_id", "1")]);
 
         }
 
-        // Test Case 2: Existing Escrow
+        // Test Case 2: Escrows from other users and repeat escrows get their own ids
         {
-            let mut deps = mock_dependencies();
-        
-            // Setup - Manually create an existing escrow
-            let existing_escrow = Escrow {
-                user: Addr::unchecked("existing_user"),
-                amount: Uint128::new(1000),
-                time: 12345, // Some block time
-            };
-            ESCROW.save(deps.as_mut().storage, &existing_escrow).unwrap();
-    
-            // Now call execute_escrow and expect an error
             let env = mock_env();
-            let user = Addr::unchecked("new_user");
-            let token = Addr::unchecked("token");
-            let amount = Uint128::new(500);
-            let time = 60u64;
-    
-            let result = execute_escrow(deps.as_mut(), env, user, token, amount, time);
-            assert_eq!(result.unwrap_err().to_string(),"User has existing escrow" );
+            let token = Addr::unchecked("token_address");
+            let other = Addr::unchecked("other_user");
+            let user = Addr::unchecked("user_addr");
+
+            let res = execute_escrow(deps.as_mut(), env.clone(), other.clone(), token.clone(), Uint128::new(1000), 120).unwrap();
+            assert_eq!(res.attributes, vec![attr("action", "escrow"), attr("escrow_id", "2")]);
+
+            let res = execute_escrow(deps.as_mut(), env.clone(), user.clone(), token, Uint128::new(700), 600).unwrap();
+            assert_eq!(res.attributes, vec![attr("action", "escrow"), attr("escrow_id", "3")]);
+
+            let user_escrows = ESCROW
+                .prefix(&user)
+                .range(deps.as_ref().storage, None, None, Order::Ascending)
+                .collect::<StdResult<Vec<_>>>()
+                .unwrap();
+            assert_eq!(user_escrows.len(), 2);
+            assert_eq!(user_escrows[0].0, 1);
+            assert_eq!(user_escrows[0].1.amount, Uint128::new(500));
+            assert_eq!(user_escrows[1].0, 3);
+            assert_eq!(user_escrows[1].1.time, env.block.time.seconds() + 600);
+
+            let other_escrow = ESCROW.load(deps.as_ref().storage, (&other, 2)).unwrap();
+            assert_eq!(other_escrow.amount, Uint128::new(1000));
+        }
+
+        // Test Case 3: A deposit claimed by a token the pool does not accept
+        {
+            let spoofed = Addr::unchecked("fake_token");
+            let user = Addr::unchecked("user_addr");
 
+            let err = execute_escrow(deps.as_mut(), mock_env(), user, spoofed, Uint128::new(500), 60).unwrap_err();
+            assert!(matches!(err, ContractError::UnacceptedToken { token } if token == "fake_token"));
         }
           
     }
@@ -258,26 +1263,78 @@ mod tests {
     #[test]
     fn test_successful_lend_to_pool() {
         let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
         let env = mock_env();
         let lender = Addr::unchecked("lender_address");
         let amount = Uint128::new(500);
         let duration = 60u64; // Duration in seconds
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, duration, 0);
 
         // Call the lend_to_pool function
-        let res = lend_to_pool(deps.as_mut(), env.clone(), lender.clone(), amount, duration).unwrap();
+        let res = lend_to_pool(deps.as_mut(), env.clone(), lender.clone(), amount, maturity_date).unwrap();
 
         // Assert the response is as expected
-        assert_eq!(res.attributes, vec![attr("action", "lend")]);
+        assert_eq!(res.attributes, vec![
+            attr("action", "lend"),
+            attr("position_id", "1"),
+            attr("rate", "0.1"),
+            attr("amount_at_maturity", "500"),
+        ]);
 
         // Assert the vault state is updated correctly
         let vault = VAULT.load(deps.as_ref().storage).unwrap();
         assert_eq!(vault.total_tokens, amount); // or `initial_vault.total_tokens + amount` if initial state is set
 
         // Assert the lender info is saved correctly
-        let lender_info = LENDERS.load(deps.as_ref().storage).unwrap();
+        let lender_info = LENDERS.load(deps.as_ref().storage, (&lender, 1)).unwrap();
         assert_eq!(lender_info.lender, lender);
         assert_eq!(lender_info.amount_lent, amount);
         assert_eq!(lender_info.maturity_date, env.block.time.seconds() + duration);
+
+        // The lend is tracked by its maturity
+        let bucket = MATURITIES.load(deps.as_ref().storage, maturity_date).unwrap();
+        assert_eq!(bucket.liquidity.total_tokens, amount);
+        assert_eq!(bucket.total_owed, amount);
+    }
+
+    #[test]
+    fn test_multiple_lenders_and_positions() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let env = mock_env();
+        let alice = Addr::unchecked("alice");
+        let bob = Addr::unchecked("bob");
+
+        for duration in [60, 120, 180] {
+            open_maturity(deps.as_mut().storage, &env, duration, 0);
+        }
+        let now = env.block.time.seconds();
+        lend_to_pool(deps.as_mut(), env.clone(), alice.clone(), Uint128::new(100), now + 60).unwrap();
+        lend_to_pool(deps.as_mut(), env.clone(), bob.clone(), Uint128::new(200), now + 120).unwrap();
+        lend_to_pool(deps.as_mut(), env.clone(), alice.clone(), Uint128::new(300), now + 180).unwrap();
+
+        // Every lend adds to the vault instead of replacing the previous lender
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(vault.total_tokens, Uint128::new(600));
+
+        // Alice holds two independent positions with their own maturity dates
+        let alice_positions = LENDERS
+            .prefix(&alice)
+            .range(deps.as_ref().storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(alice_positions.len(), 2);
+        assert_eq!(alice_positions[0].0, 1);
+        assert_eq!(alice_positions[0].1.maturity_date, env.block.time.seconds() + 60);
+        assert_eq!(alice_positions[1].0, 3);
+        assert_eq!(alice_positions[1].1.maturity_date, env.block.time.seconds() + 180);
+
+        let bob_position = LENDERS.load(deps.as_ref().storage, (&bob, 2)).unwrap();
+        assert_eq!(bob_position.amount_lent, Uint128::new(200));
+
+        // Bob cannot release Alice's position
+        let err = release_from_pool(deps.as_mut(), env, bob, 1).unwrap_err();
+        assert!(matches!(err, ContractError::NoLenderPosition {}));
     }
 
     #[test]
@@ -285,29 +1342,115 @@ mod tests {
         let mut deps = mock_dependencies();
 
         // Setup initial vault state
-        let initial_vault = Vault { total_tokens: Uint128::new(1000) };
+        let initial_vault = Vault { total_tokens: Uint128::new(1000), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         let env = mock_env();
         let borrower = Addr::unchecked("borrower_address");
         let amount = Uint128::new(500);
         let duration = 60u64; // Duration in seconds
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, duration, 1000);
+        post_collateral(deps.as_mut().storage, &borrower, 1000);
 
         // Call the borrow_from_pool function
-        let res = borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), amount, duration).unwrap();
+        let res = borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), amount, maturity_date).unwrap();
 
         // Assert the response is as expected
-        assert_eq!(res.attributes, vec![attr("action", "borrow")]);
+        assert_eq!(res.attributes, vec![attr("action", "borrow"), attr("loan_id", "1")]);
+
+        // Assert the borrowed tokens are paid out to the borrower
+        assert_eq!(res.messages.len(), 1);
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_cw20(&Addr::unchecked("token_address"), &borrower, amount).unwrap()
+        );
 
         // Assert the vault state is updated correctly
         let vault = VAULT.load(deps.as_ref().storage).unwrap();
         assert_eq!(vault.total_tokens, Uint128::new(500)); // 1000 - 500
 
         // Assert the borrower info is saved correctly
-        let borrower_info = BORROWERS.load(deps.as_ref().storage).unwrap();
+        let borrower_info = BORROWERS.load(deps.as_ref().storage, (&borrower, 1)).unwrap();
         assert_eq!(borrower_info.borrower, borrower);
-        assert_eq!(borrower_info.amount_borrowed, amount);
+        assert_eq!(borrower_info.principal, amount);
+        assert_eq!(borrower_info.interest_accrued, Uint128::zero());
+        assert_eq!(borrower_info.start_time, env.block.time.seconds());
         assert_eq!(borrower_info.maturity_date, env.block.time.seconds() + duration);
+        assert_eq!(borrower_info.status, LoanStatus::Open);
+
+        let bucket = MATURITIES.load(deps.as_ref().storage, maturity_date).unwrap();
+        assert_eq!(bucket.liquidity.total_tokens, Uint128::new(500));
+        assert_eq!(bucket.liquidity.total_borrowed, amount);
+    }
+
+    #[test]
+    fn test_borrower_keeps_multiple_loans() {
+        let mut deps = mock_dependencies();
+        VAULT.save(deps.as_mut().storage, &Vault { total_tokens: Uint128::new(1000), ..Vault::default() }).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+
+        let env = mock_env();
+        let borrower = Addr::unchecked("borrower_address");
+
+        let short = open_maturity(deps.as_mut().storage, &env, 60, 500);
+        let long = open_maturity(deps.as_mut().storage, &env, 120, 500);
+        post_collateral(deps.as_mut().storage, &borrower, 600);
+        post_collateral(deps.as_mut().storage, &Addr::unchecked("other"), 100);
+
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(100), short).unwrap();
+        borrow_from_pool(deps.as_mut(), env.clone(), Addr::unchecked("other"), Uint128::new(50), short).unwrap();
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(200), long).unwrap();
+
+        // The second borrow does not overwrite the first one
+        let loans = BORROWERS
+            .prefix(&borrower)
+            .range(deps.as_ref().storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()
+            .unwrap();
+        assert_eq!(loans.len(), 2);
+        assert_eq!(loans[0].0, 1);
+        assert_eq!(loans[0].1.principal, Uint128::new(100));
+        assert_eq!(loans[1].0, 3);
+        assert_eq!(loans[1].1.principal, Uint128::new(200));
+        assert_eq!(loans[1].1.maturity_date, env.block.time.seconds() + 120);
+
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(vault.total_tokens, Uint128::new(650));
+    }
+
+    #[test]
+    fn test_borrow_outside_limits() {
+        let mut deps = mock_dependencies();
+        VAULT.save(deps.as_mut().storage, &Vault { total_tokens: Uint128::new(10_000_000), ..Vault::default() }).unwrap();
+        let config = Config { min_duration: 60, max_borrow_amount: Uint128::new(1000), ..mock_config() };
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+        let env = mock_env();
+        let too_short = open_maturity(deps.as_mut().storage, &env, 59, 10_000_000);
+        let too_long = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR + 1, 10_000_000);
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, 60, 10_000_000);
+
+        let borrower = Addr::unchecked("borrower_address");
+        let err = borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(100), too_short).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidDuration { duration: 59 }));
+
+        let err = borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(100), too_long).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidDuration { .. }));
+
+        let err = borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(1001), maturity_date).unwrap_err();
+        assert!(matches!(err, ContractError::BorrowLimitExceeded { .. }));
+
+        // 150 of collateral at a 50% LTV backs 75, the second borrow would take the debt to 100
+        post_collateral(deps.as_mut().storage, &borrower, 150);
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(50), maturity_date).unwrap();
+        let err = borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(50), maturity_date).unwrap_err();
+        assert!(matches!(err, ContractError::Undercollateralized { debt, borrow_capacity }
+            if debt == Uint128::new(100) && borrow_capacity == Uint128::new(75)));
+
+        // Maturities the owner never created can not be borrowed from
+        let err = borrow_from_pool(deps.as_mut(), env, borrower, Uint128::new(100), maturity_date + 1).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityNotFound { .. }));
     }
 
     #[test]
@@ -315,16 +1458,17 @@ mod tests {
         let mut deps = mock_dependencies();
 
         // Setup initial vault state with insufficient funds
-        let initial_vault = Vault { total_tokens: Uint128::new(300) };
+        let initial_vault = Vault { total_tokens: Uint128::new(300), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         let env = mock_env();
         let borrower = Addr::unchecked("borrower_address");
         let amount = Uint128::new(500); // More than what's in the vault
-        let duration = 60u64;
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, 60, 300);
 
         // Call the borrow_from_pool function
-        let result = borrow_from_pool(deps.as_mut(), env, borrower, amount, duration);
+        let result = borrow_from_pool(deps.as_mut(), env, borrower, amount, maturity_date);
 
         // Check for InsufficientFunds error
         assert!(matches!(result, Err(ContractError::InsufficientFunds {})));
@@ -338,28 +1482,171 @@ mod tests {
         let lender_info = LenderInfo {
             lender: Addr::unchecked("lender_address"),
             amount_lent: Uint128::new(500),
+            start_time: 0,
             maturity_date: 1, // Past date
+            rate: Decimal::zero(),
+            amount_at_maturity: Uint128::new(500),
+            supply_index: Decimal256::one(),
+            amount_paid: Uint128::zero(),
+            settled: false,
         };
-        LENDERS.save(deps.as_mut().storage, &lender_info).unwrap();
+        LENDERS.save(deps.as_mut().storage, (&lender_info.lender, 1), &lender_info).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         // Setup initial vault state
-        let initial_vault = Vault { total_tokens: Uint128::new(1000) };
+        let initial_vault = Vault { total_tokens: Uint128::new(1000), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
+        let bucket = MaturityBucket {
+            liquidity: initial_vault,
+            total_principal: Uint128::new(500),
+            total_owed: Uint128::new(500),
+            ..MaturityBucket::new(1)
+        };
+        MATURITIES.save(deps.as_mut().storage, 1, &bucket).unwrap();
 
         let mut env = mock_env();
         env.block.time = Timestamp::from_seconds(2); // Current time after maturity date
 
         // Call the release_from_pool function
-        let res = release_from_pool(deps.as_mut(), env, lender_info.lender.clone()).unwrap();
+        let res = release_from_pool(deps.as_mut(), env.clone(), lender_info.lender.clone(), 1).unwrap();
 
         // Assert the response and storage updates
-        assert_eq!(res.attributes, vec![attr("action", "release")]);
+        assert_eq!(res.attributes, vec![attr("action", "release"), attr("position_id", "1"), attr("amount", "500")]);
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_cw20(&Addr::unchecked("token_address"), &lender_info.lender, Uint128::new(500)).unwrap()
+        );
 
         let vault = VAULT.load(deps.as_ref().storage).unwrap();
         assert_eq!(vault.total_tokens, Uint128::new(500)); // 1000 - 500
+        let bucket = MATURITIES.load(deps.as_ref().storage, 1).unwrap();
+        assert_eq!(bucket.liquidity.total_tokens, Uint128::new(500));
+        assert_eq!(bucket.total_paid, Uint128::new(500));
+        assert!(bucket.outstanding().is_zero());
+
+        // The position is kept as paid out and cannot be withdrawn again
+        assert!(LENDERS.load(deps.as_ref().storage, (&lender_info.lender, 1)).unwrap().settled);
+        let err = release_from_pool(deps.as_mut(), env, lender_info.lender.clone(), 1).unwrap_err();
+        assert!(matches!(err, ContractError::PositionSettled { position_id: 1 }));
+    }
+
+    #[test]
+    fn test_release_pays_interest_earned_over_term() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let mut env = mock_env();
+        let lender = Addr::unchecked("lender_address");
+        let borrower = Addr::unchecked("borrower_address");
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+
+        // The lender locks 10% for the year and the borrower takes the whole liquidity at 10%
+        lend_to_pool(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date).unwrap();
+        post_collateral(deps.as_mut().storage, &borrower, 3000);
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(1000), maturity_date).unwrap();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        repay_loan(deps.as_mut(), env.clone(), borrower, 1, Uint128::new(1200)).unwrap();
+
+        // Withdrawing late does not earn more than the agreed term
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        let res = release_from_pool(deps.as_mut(), env, lender.clone(), 1).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_cw20(&Addr::unchecked("token_address"), &lender, Uint128::new(1100)).unwrap()
+        );
+
+        assert_eq!(VAULT.load(deps.as_ref().storage).unwrap().total_tokens, vault.total_tokens - Uint128::new(1100));
+        let bucket = MATURITIES.load(deps.as_ref().storage, maturity_date).unwrap();
+        assert!(bucket.outstanding().is_zero());
+        assert!(LENDERS.load(deps.as_ref().storage, (&lender, 1)).unwrap().settled);
+    }
+
+    #[test]
+    fn test_settled_maturity_pays_lenders_pro_rata() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let mut env = mock_env();
+        let alice = Addr::unchecked("alice");
+        let bob = Addr::unchecked("bob");
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+        lend_to_pool(deps.as_mut(), env.clone(), alice.clone(), Uint128::new(1000), maturity_date).unwrap();
+        lend_to_pool(deps.as_mut(), env.clone(), bob.clone(), Uint128::new(3000), maturity_date).unwrap();
+
+        // Nobody borrowed, the bucket holds the principal but none of the 400 of interest
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        let err = release_from_pool(deps.as_mut(), env.clone(), alice.clone(), 1).unwrap_err();
+        assert!(matches!(err, ContractError::MaturityNotSettled { .. }));
+        let res = settle_maturity(deps.as_mut(), env.clone(), maturity_date).unwrap();
+        assert!(res.attributes.contains(&attr("shortfall", "400")));
+
+        let res = release_from_pool(deps.as_mut(), env.clone(), bob.clone(), 2).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "3000")));
+        let res = release_from_pool(deps.as_mut(), env.clone(), alice.clone(), 1).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "1000")));
+        assert!(!LENDERS.load(deps.as_ref().storage, (&alice, 1)).unwrap().settled);
+
+        // Half of the interest came in, each lender gets half of theirs whatever the order
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+        lend_to_pool(deps.as_mut(), env.clone(), alice.clone(), Uint128::new(1000), maturity_date).unwrap();
+        lend_to_pool(deps.as_mut(), env.clone(), bob.clone(), Uint128::new(3000), maturity_date).unwrap();
+        let mut bucket = MATURITIES.load(deps.as_ref().storage, maturity_date).unwrap();
+        bucket.liquidity.total_tokens += Uint128::new(200);
+        MATURITIES.save(deps.as_mut().storage, maturity_date, &bucket).unwrap();
+        let mut vault = VAULT.load(deps.as_ref().storage).unwrap();
+        vault.total_tokens += Uint128::new(200);
+        VAULT.save(deps.as_mut().storage, &vault).unwrap();
+
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        settle_maturity(deps.as_mut(), env.clone(), maturity_date).unwrap();
+        let res = release_from_pool(deps.as_mut(), env.clone(), alice.clone(), 3).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "1050")));
+        let res = release_from_pool(deps.as_mut(), env.clone(), bob, 4).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "3150")));
+
+        // Once the bucket is empty there is nothing more to withdraw until a repayment comes in
+        let err = release_from_pool(deps.as_mut(), env, alice, 3).unwrap_err();
+        assert!(matches!(err, ContractError::NothingToWithdraw { position_id: 3 }));
+        assert!(VAULT.load(deps.as_ref().storage).unwrap().total_tokens.is_zero());
+    }
 
-        // Check if lender's info is removed
-        assert!(LENDERS.load(deps.as_ref().storage).is_err());
+    #[test]
+    fn test_repayment_after_partial_withdrawal_reaches_every_lender() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let mut env = mock_env();
+        let alice = Addr::unchecked("alice");
+        let bob = Addr::unchecked("bob");
+        let borrower = Addr::unchecked("borrower");
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+        lend_to_pool(deps.as_mut(), env.clone(), alice.clone(), Uint128::new(1000), maturity_date).unwrap();
+        lend_to_pool(deps.as_mut(), env.clone(), bob.clone(), Uint128::new(1000), maturity_date).unwrap();
+        post_collateral(deps.as_mut().storage, &borrower, 3000);
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(1000), maturity_date).unwrap();
+
+        // The loan is still out at maturity, alice takes her share of the 1000 left
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        settle_maturity(deps.as_mut(), env.clone(), maturity_date).unwrap();
+        let res = release_from_pool(deps.as_mut(), env.clone(), alice.clone(), 1).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "500")));
+        let position = LENDERS.load(deps.as_ref().storage, (&alice, 1)).unwrap();
+        assert_eq!(position.amount_paid, Uint128::new(500));
+        assert!(!position.settled);
+
+        // The late repayment brings 1100 back, both lenders end up with the same 1050 and nothing
+        // is swept while they are still owed interest
+        repay_loan(deps.as_mut(), env.clone(), borrower, 1, Uint128::new(2000)).unwrap();
+        let res = release_from_pool(deps.as_mut(), env.clone(), bob.clone(), 2).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "1050")));
+        let res = release_from_pool(deps.as_mut(), env.clone(), alice.clone(), 1).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "550")));
+        assert_eq!(LENDERS.load(deps.as_ref().storage, (&alice, 1)).unwrap().amount_paid, Uint128::new(1050));
+        assert_eq!(LENDERS.load(deps.as_ref().storage, (&bob, 2)).unwrap().amount_paid, Uint128::new(1050));
+
+        let bucket = MATURITIES.load(deps.as_ref().storage, maturity_date).unwrap();
+        assert_eq!(bucket.total_paid, Uint128::new(2100));
+        assert_eq!(bucket.outstanding(), Uint128::new(100));
+        assert!(bucket.liquidity.total_tokens.is_zero());
+        assert!(bucket.liquidity.total_tokens.is_zero());
     }
 
     #[test]
@@ -369,19 +1656,26 @@ mod tests {
         let lender_info = LenderInfo {
             lender: Addr::unchecked("lender_address"),
             amount_lent: Uint128::new(500),
+            start_time: 0,
             maturity_date: 2, // Past date
+            rate: Decimal::zero(),
+            amount_at_maturity: Uint128::new(500),
+            supply_index: Decimal256::one(),
+            amount_paid: Uint128::zero(),
+            settled: false,
         };
-        LENDERS.save(deps.as_mut().storage, &lender_info).unwrap();
+        LENDERS.save(deps.as_mut().storage, (&lender_info.lender, 1), &lender_info).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         // Setup initial vault state
-        let initial_vault = Vault { total_tokens: Uint128::new(1000) };
+        let initial_vault = Vault { total_tokens: Uint128::new(1000), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
 
         let mut env = mock_env();
         env.block.time = Timestamp::from_seconds(1); // Before the maturity date
 
         // Attempt to release funds before maturity
-        let result = release_from_pool(deps.as_mut(), env, lender_info.lender);
+        let result = release_from_pool(deps.as_mut(), env, lender_info.lender, 1);
 
         // Check for DurationNotMet error
         assert_eq!(result.unwrap_err().to_string(),"Duration Not Met");
@@ -394,108 +1688,198 @@ mod tests {
         let lender_info = LenderInfo {
             lender: Addr::unchecked("lender_address"),
             amount_lent: Uint128::new(500),
+            start_time: 0,
             maturity_date: 1, // Past date
+            rate: Decimal::zero(),
+            amount_at_maturity: Uint128::new(500),
+            supply_index: Decimal256::one(),
+            amount_paid: Uint128::zero(),
+            settled: false,
         };
-        LENDERS.save(deps.as_mut().storage, &lender_info).unwrap();
+        LENDERS.save(deps.as_mut().storage, (&lender_info.lender, 1), &lender_info).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         // Setup vault with insufficient funds
-        let initial_vault = Vault { total_tokens: Uint128::new(300) };
+        let initial_vault = Vault { total_tokens: Uint128::new(300), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
+        let bucket = MaturityBucket {
+            liquidity: initial_vault,
+            total_principal: Uint128::new(500),
+            total_owed: Uint128::new(500),
+            ..MaturityBucket::new(1)
+        };
+        MATURITIES.save(deps.as_mut().storage, 1, &bucket).unwrap();
 
         let mut env = mock_env();
         env.block.time = Timestamp::from_seconds(2); // After the maturity date
 
-        // Attempt to release more funds than available
-        let result = release_from_pool(deps.as_mut(), env, lender_info.lender);
+        // A short maturity has to be settled before anyone is paid
+        let result = release_from_pool(deps.as_mut(), env.clone(), lender_info.lender.clone(), 1);
+        assert_eq!(result.unwrap_err().to_string(), "Maturity 1 cannot pay all its lenders, it has to be settled first");
 
-        // Check for InsufficientFunds error
-        assert_eq!(result.unwrap_err().to_string(),"Insufficent Funds");
+        // The lender then gets what is left of the principal
+        settle_maturity(deps.as_mut(), env.clone(), 1).unwrap();
+        let res = release_from_pool(deps.as_mut(), env, lender_info.lender, 1).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "300")));
     }
 
     #[test]
     fn test_successful_earn_tokens_into_pool() {
         let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         // Setup initial vault state
-        let initial_vault = Vault { total_tokens: Uint128::new(1000) };
+        let initial_vault = Vault { total_tokens: Uint128::new(1000), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
 
-        // Setup initial earnings state
-        let initial_earnings = Earnings {
-            user: Addr::unchecked("some_user"),
-            amount_supplied: Uint128::new(200),
-            last_updated: 1,
-        };
-        EARNINGS.save(deps.as_mut().storage, &initial_earnings).unwrap();
-
-        let user = Addr::unchecked("new_user");
-        let amount = Uint128::new(300);
+        let alice = Addr::unchecked("alice");
+        let bob = Addr::unchecked("bob");
 
         // Call the earn_tokens_into_pool function
-        let res = earn_tokens_into_pool(deps.as_mut(), mock_env(), user.clone(), amount).unwrap();
+        let res = earn_tokens_into_pool(deps.as_mut(), alice.clone(), Uint128::new(200)).unwrap();
 
-        // Assert the response
-        assert_eq!(res.attributes, vec![attr("action", "earn")]);
+        // Assert the response, the first deposit mints shares one to one
+        assert_eq!(res.attributes, vec![attr("action", "earn"), attr("shares", "200")]);
 
-        // Assert the vault state is updated correctly
-        let vault = VAULT.load(deps.as_ref().storage).unwrap();
-        assert_eq!(vault.total_tokens, Uint128::new(1300)); // 1000 + 300
+        earn_tokens_into_pool(deps.as_mut(), bob.clone(), Uint128::new(300)).unwrap();
+
+        // Earn deposits are not lent out, the vault only holds the maturities' tokens
+        assert_eq!(VAULT.load(deps.as_ref().storage).unwrap(), initial_vault);
+
+        // Deposits from different users are no longer merged
+        assert_eq!(EARN_SHARES.load(deps.as_ref().storage, &alice).unwrap(), Uint128::new(200));
+        assert_eq!(EARN_SHARES.load(deps.as_ref().storage, &bob).unwrap(), Uint128::new(300));
+        let pool = EARN_POOL.load(deps.as_ref().storage).unwrap();
+        assert_eq!(pool.total_assets, Uint128::new(500));
+        assert_eq!(pool.total_shares, Uint128::new(500));
+    }
 
-        // Assert the user earnings is updated correctly
-        let user_earnings = EARNINGS.load(deps.as_ref().storage).unwrap();
-        assert_eq!(user_earnings.user, user);
-        assert_eq!(user_earnings.amount_supplied, Uint128::new(500)); // 200 + 300
+    #[test]
+    fn test_earn_deposit_after_yield_mints_fewer_shares() {
+        let mut deps = mock_dependencies();
+        let config = Config { term_rates: vec![TermRate { duration: 0, rate: Decimal::percent(5) }], ..mock_config() };
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        let mut env = mock_env();
+
+        let alice = Addr::unchecked("alice");
+        let bob = Addr::unchecked("bob");
+        earn_tokens_into_pool(deps.as_mut(), alice.clone(), Uint128::new(1000)).unwrap();
+
+        // Lenders are owed 5% while the borrower pays 10%, the difference is collected interest
+        let lender = Addr::unchecked("lender_address");
+        let borrower = Addr::unchecked("borrower_address");
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+        lend_to_pool(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(2000), maturity_date).unwrap();
+        post_collateral(deps.as_mut().storage, &borrower, 5000);
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(2000), maturity_date).unwrap();
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR);
+        repay_loan(deps.as_mut(), env.clone(), borrower, 1, Uint128::new(2200)).unwrap();
+
+        // Nothing is paid to earners before the maturity settles
+        assert_eq!(EARN_POOL.load(deps.as_ref().storage).unwrap().total_assets, Uint128::new(1000));
+        let res = settle_maturity(deps.as_mut(), env.clone(), maturity_date).unwrap();
+        assert!(res.attributes.contains(&attr("surplus", "100")));
+
+        // The surplus lifts the price per share to 1.1, the lender is still paid in full
+        earn_tokens_into_pool(deps.as_mut(), bob.clone(), Uint128::new(1100)).unwrap();
+        assert_eq!(EARN_SHARES.load(deps.as_ref().storage, &bob).unwrap(), Uint128::new(1000));
+        let pool = EARN_POOL.load(deps.as_ref().storage).unwrap();
+        assert_eq!(pool.total_assets, Uint128::new(2200));
+        assert_eq!(pool.total_shares, Uint128::new(2000));
+        assert_eq!(pool.convert_to_assets(Uint128::new(1000)), Uint128::new(1100));
+
+        let res = release_from_pool(deps.as_mut(), env, lender, 1).unwrap();
+        assert!(res.attributes.contains(&attr("amount", "2100")));
+        assert!(VAULT.load(deps.as_ref().storage).unwrap().total_tokens.is_zero());
     }
 
     #[test]
     fn test_successful_withdraw_from_pool_for_earn() {
         let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
-        // Setup user earnings and vault
-        let user_earnings = Earnings {
-            user: Addr::unchecked("user_address"),
-            amount_supplied: Uint128::new(500),
-            last_updated: 1,
-        };
-        EARNINGS.save(deps.as_mut().storage, &user_earnings).unwrap();
+        // Setup user shares after 25 of yield and the lenders' vault
+        let user = Addr::unchecked("user_address");
+        EARN_POOL.save(deps.as_mut().storage, &EarnPool {
+            total_assets: Uint128::new(525),
+            total_shares: Uint128::new(500),
+        }).unwrap();
+        EARN_SHARES.save(deps.as_mut().storage, &user, &Uint128::new(500)).unwrap();
 
-        let initial_vault = Vault { total_tokens: Uint128::new(1000) };
+        let initial_vault = Vault { total_tokens: Uint128::new(1000), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
 
         // Call the withdraw_from_pool_for_earn function
-        let res = withdraw_from_pool_for_earn(deps.as_mut(), mock_env(), user_earnings.user.clone()).unwrap();
+        let res = withdraw_from_pool_for_earn(deps.as_mut(), user.clone(), None).unwrap();
 
         // Assert the response
-        assert_eq!(res.attributes, vec![attr("action", "withdraw for earn")]);
-
-        // Assert the vault state is updated correctly
-        let vault = VAULT.load(deps.as_ref().storage).unwrap();
-        assert_eq!(vault.total_tokens, Uint128::new(500)); // 1000 - 500
+        assert_eq!(res.attributes, vec![attr("action", "withdraw for earn"), attr("amount", "525"), attr("shares", "500")]);
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_cw20(&Addr::unchecked("token_address"), &user, Uint128::new(525)).unwrap()
+        );
+
+        // The lenders' tokens are left alone
+        assert_eq!(VAULT.load(deps.as_ref().storage).unwrap(), initial_vault);
+
+        // Assert the user shares are burned
+        assert!(EARN_SHARES.may_load(deps.as_ref().storage, &user).unwrap().is_none());
+        let pool = EARN_POOL.load(deps.as_ref().storage).unwrap();
+        assert_eq!(pool.total_assets, Uint128::zero());
+        assert_eq!(pool.total_shares, Uint128::zero());
+    }
 
-        // Assert the user earnings is reset
-        assert!(EARNINGS.load(deps.as_ref().storage).is_err()); // Should be removed
+    #[test]
+    fn test_partial_withdraw_from_pool_for_earn() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+
+        let user = Addr::unchecked("user_address");
+        EARN_POOL.save(deps.as_mut().storage, &EarnPool {
+            total_assets: Uint128::new(1100),
+            total_shares: Uint128::new(1000),
+        }).unwrap();
+        EARN_SHARES.save(deps.as_mut().storage, &user, &Uint128::new(1000)).unwrap();
+        VAULT.save(deps.as_mut().storage, &Vault { total_tokens: Uint128::new(5000), ..Vault::default() }).unwrap();
+
+        // Claim is 1100 at a price of 1.1, withdrawing 400 burns 364 shares (rounded up)
+        withdraw_from_pool_for_earn(deps.as_mut(), user.clone(), Some(Uint128::new(400))).unwrap();
+        assert_eq!(EARN_SHARES.load(deps.as_ref().storage, &user).unwrap(), Uint128::new(636));
+        let pool = EARN_POOL.load(deps.as_ref().storage).unwrap();
+        assert_eq!(pool.total_assets, Uint128::new(700));
+        assert_eq!(pool.total_shares, Uint128::new(636));
+
+        // Cannot withdraw more than the claim
+        let err = withdraw_from_pool_for_earn(deps.as_mut(), user.clone(), Some(Uint128::new(701))).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficientFunds {}));
+
+        // Another user has no claim on this deposit
+        let err = withdraw_from_pool_for_earn(deps.as_mut(), Addr::unchecked("other"), None).unwrap_err();
+        assert!(matches!(err, ContractError::NoEarnDeposit {}));
     }
 
     #[test]
     fn test_insufficient_funds_withdraw_from_pool_for_earn() {
         let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
-        // Setup user earnings and insufficient vault funds
-        let user_earnings = Earnings {
-            user: Addr::unchecked("user_address"),
-            amount_supplied: Uint128::new(600),
-            last_updated: 1,
-        };
-        EARNINGS.save(deps.as_mut().storage, &user_earnings).unwrap();
+        // Setup user shares next to a vault full of lender money
+        let user = Addr::unchecked("user_address");
+        EARN_POOL.save(deps.as_mut().storage, &EarnPool {
+            total_assets: Uint128::new(600),
+            total_shares: Uint128::new(600),
+        }).unwrap();
+        EARN_SHARES.save(deps.as_mut().storage, &user, &Uint128::new(600)).unwrap();
 
-        let initial_vault = Vault { total_tokens: Uint128::new(500) }; // Less than amount_supplied
+        let initial_vault = Vault { total_tokens: Uint128::new(5000), ..Vault::default() };
         VAULT.save(deps.as_mut().storage, &initial_vault).unwrap();
 
-        // Call the withdraw_from_pool_for_earn function
-        let result = withdraw_from_pool_for_earn(deps.as_mut(), mock_env(), user_earnings.user);
+        // Withdrawing past the pool's own assets fails
+        let result = withdraw_from_pool_for_earn(deps.as_mut(), user, Some(Uint128::new(1100)));
 
         // Check for InsufficientFunds error
         assert!(matches!(result, Err(ContractError::InsufficientFunds {})));
+        assert_eq!(VAULT.load(deps.as_ref().storage).unwrap(), initial_vault);
     }
 
     #[test]
@@ -508,42 +1892,32 @@ mod tests {
             amount: Uint128::new(500),
             time: 1, // Past time
         };
-        ESCROW.save(deps.as_mut().storage, &escrow).unwrap();
+        ESCROW.save(deps.as_mut().storage, (&escrow.user, 1), &escrow).unwrap();
 
-        let config = Config {
-            owner : Addr::unchecked("input"),
-            token: Addr::unchecked("token_address"),
-           
-        };
-        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         let mut env = mock_env();
         env.block.time = Timestamp::from_seconds(2); // Current time after escrow time
 
         // Call the execute_redeem function
-        let res = execute_redeem(deps.as_mut(), env, escrow.user.clone()).unwrap();
+        let res = execute_redeem(deps.as_mut(), env, escrow.user.clone(), 1).unwrap();
 
         // Assert the response
         assert_eq!(res.messages.len(), 1); // Should have one message
-        assert_eq!(res.attributes, vec![attr("action", "redeem")]);
+        assert_eq!(res.attributes, vec![attr("action", "redeem"), attr("escrow_id", "1")]);
 
         // Assert the escrow is removed
-        assert!(ESCROW.may_load(deps.as_ref().storage).is_ok());
+        assert!(ESCROW.may_load(deps.as_ref().storage, (&escrow.user, 1)).unwrap().is_none());
     }
 
     #[test]
     fn test_no_existing_escrow_execute_redeem() {
         let mut deps = mock_dependencies();
 
-        let config = Config {
-            owner : Addr::unchecked("input"),
-            token: Addr::unchecked("token_address"),
-           
-        };
-        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         // Call the execute_redeem function without setting up escrow
-        let result = execute_redeem(deps.as_mut(), mock_env(), Addr::unchecked("user_address"));
+        let result = execute_redeem(deps.as_mut(), mock_env(), Addr::unchecked("user_address"), 1);
 
         // Check for NoExistingEscrow error
         assert!(matches!(result, Err(ContractError::NoExistingEscrow {})));
@@ -553,12 +1927,7 @@ mod tests {
     fn test_not_expired_execute_redeem() {
         let mut deps = mock_dependencies();
 
-        let config = Config {
-            owner : Addr::unchecked("input"),
-            token: Addr::unchecked("token_address"),
-           
-        };
-        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
 
         // Setup escrow with future time
         let escrow = Escrow {
@@ -566,41 +1935,573 @@ mod tests {
             amount: Uint128::new(500),
             time: 10, // Future time
         };
-        ESCROW.save(deps.as_mut().storage, &escrow).unwrap();
+        ESCROW.save(deps.as_mut().storage, (&escrow.user, 1), &escrow).unwrap();
 
         let mut env = mock_env();
         env.block.time = Timestamp::from_seconds(2); // Before the escrow time
 
         // Attempt to redeem before time
-        let result = execute_redeem(deps.as_mut(), env, escrow.user);
+        let result = execute_redeem(deps.as_mut(), env, escrow.user, 1);
 
         // Check for NotExpired error
         assert!(matches!(result, Err(ContractError::NotExpired {})));
     }
 
+    fn setup_open_loan(deps: DepsMut, principal: u128) -> Addr {
+        CONFIG.save(deps.storage, &mock_config()).unwrap();
+
+        let borrower = Addr::unchecked("borrower_address");
+        let loan = BorrowerInfo {
+            borrower: borrower.clone(),
+            principal: Uint128::new(principal),
+            interest_accrued: Uint128::zero(),
+            amount_repaid: Uint128::zero(),
+            start_time: 0,
+            maturity_date: SECONDS_PER_YEAR,
+            borrow_index: Decimal256::one(),
+            fixed_rate: None,
+            status: LoanStatus::Open,
+        };
+        BORROWERS.save(deps.storage, (&borrower, 1), &loan).unwrap();
+        INTEREST_INDEX.save(deps.storage, &InterestIndex::new(0)).unwrap();
+        let liquidity = Vault { total_tokens: Uint128::zero(), total_borrowed: Uint128::new(principal) };
+        VAULT.save(deps.storage, &liquidity).unwrap();
+        MATURITIES.save(deps.storage, SECONDS_PER_YEAR, &MaturityBucket { liquidity, ..MaturityBucket::new(SECONDS_PER_YEAR) }).unwrap();
+        borrower
+    }
+
     #[test]
-    fn test_send_tokens() {
-        let to_address = Addr::unchecked("recipient_address");
-        let amount = coins(100, "token");
-        let action = "test_action";
+    fn test_partial_repay_covers_interest_first() {
+        let mut deps = mock_dependencies();
+        let borrower = setup_open_loan(deps.as_mut(), 1000);
+
+        // Half a year at 10% accrues 50 interest
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(SECONDS_PER_YEAR / 2);
+
+        let res = repay_loan(deps.as_mut(), env, borrower.clone(), 1, Uint128::new(150)).unwrap();
+        assert_eq!(res.messages.len(), 0);
+        assert_eq!(res.attributes, vec![
+            attr("action", "repay"),
+            attr("loan_id", "1"),
+            attr("interest_paid", "50"),
+            attr("principal_paid", "100"),
+            attr("fee", "0"),
+            attr("remaining_debt", "900"),
+        ]);
+
+        let loan = BORROWERS.load(deps.as_ref().storage, (&borrower, 1)).unwrap();
+        assert_eq!(loan.interest_accrued, Uint128::zero());
+        assert_eq!(loan.principal, Uint128::new(900));
+        assert_eq!(loan.amount_repaid, Uint128::new(150));
+        assert_eq!(loan.status, LoanStatus::Open);
+
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(vault.total_tokens, Uint128::new(150));
 
-        // Call the send_tokens function
-        let response = send_tokens(to_address.clone(), amount.clone(), action);
+        // The repayment refills the loan's maturity
+        let bucket = MATURITIES.load(deps.as_ref().storage, SECONDS_PER_YEAR).unwrap();
+        assert_eq!(bucket.liquidity.total_tokens, Uint128::new(150));
+        assert_eq!(bucket.liquidity.total_borrowed, Uint128::new(900));
+    }
+
+    #[test]
+    fn test_full_repay_closes_loan_and_refunds_excess() {
+        let mut deps = mock_dependencies();
+        let borrower = setup_open_loan(deps.as_mut(), 1000);
 
-        // Check the message in the response
-        assert_eq!(response.messages.len(), 1);
-        match &response.messages[0].msg {
-            CosmosMsg::Bank(BankMsg::Send { to_address: msg_to_address, amount: msg_amount }) => {
-                assert_eq!(msg_to_address, &to_address);
-                assert_eq!(msg_amount, &amount);
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(SECONDS_PER_YEAR);
+
+        // Debt is 1000 principal + 100 interest, 50 is paid on top
+        let res = repay_loan(deps.as_mut(), env.clone(), borrower.clone(), 1, Uint128::new(1150)).unwrap();
+        assert_eq!(res.messages.len(), 1);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                assert_eq!(contract_addr, "token_address");
+                assert_eq!(
+                    from_binary::<Cw20ExecuteMsg>(msg).unwrap(),
+                    Cw20ExecuteMsg::Transfer { recipient: borrower.to_string(), amount: Uint128::new(50) }
+                );
             },
             _ => panic!("Unexpected message type"),
         }
 
-        // Check the attributes
-        assert_eq!(response.attributes, vec![
-            attr("action", action),
-            attr("to", to_address.as_str()),
+        let loan = BORROWERS.load(deps.as_ref().storage, (&borrower, 1)).unwrap();
+        assert_eq!(loan.outstanding_debt(), Uint128::zero());
+        assert_eq!(loan.amount_repaid, Uint128::new(1100));
+        assert_eq!(loan.status, LoanStatus::Repaid);
+
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(vault.total_tokens, Uint128::new(1100));
+
+        // A repaid loan cannot be repaid again
+        let err = repay_loan(deps.as_mut(), env, borrower, 1, Uint128::new(1)).unwrap_err();
+        assert!(matches!(err, ContractError::LoanNotOpen {}));
+    }
+
+    #[test]
+    fn test_repay_unknown_loan() {
+        let mut deps = mock_dependencies();
+        let borrower = setup_open_loan(deps.as_mut(), 1000);
+
+        let err = repay_loan(deps.as_mut(), mock_env(), borrower, 2, Uint128::new(10)).unwrap_err();
+        assert!(matches!(err, ContractError::LoanNotFound {}));
+    }
+
+    #[test]
+    fn test_update_config() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+
+        let update = ConfigUpdate {
+            base_interest_rate: Some(Decimal::percent(12)),
+            fee_percentage: Some(Decimal::zero()), // unchanged
+            max_borrow_amount: Some(Uint128::new(5000)),
+            ..ConfigUpdate::default()
+        };
+
+        // Only the owner can update the config
+        let err = update_config(deps.as_mut(), Addr::unchecked("someone"), update.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let res = update_config(deps.as_mut(), Addr::unchecked("input"), update).unwrap();
+        assert_eq!(res.attributes, vec![
+            attr("action", "update_config"),
+            attr("old_base_interest_rate", "0.1"),
+            attr("new_base_interest_rate", "0.12"),
+            attr("old_max_borrow_amount", "1000000"),
+            attr("new_max_borrow_amount", "5000"),
         ]);
+        let config = CONFIG.load(deps.as_ref().storage).unwrap();
+        assert_eq!(config.base_interest_rate, Decimal::percent(12));
+        assert_eq!(config.max_borrow_amount, Uint128::new(5000));
+
+        // Invalid values are rejected
+        let update = ConfigUpdate { fee_percentage: Some(Decimal::percent(150)), ..ConfigUpdate::default() };
+        let err = update_config(deps.as_mut(), Addr::unchecked("input"), update).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidConfig { .. }));
+    }
+
+    #[test]
+    fn test_two_step_ownership_transfer() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let owner = Addr::unchecked("input");
+        let multisig = Addr::unchecked("multisig");
+
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(1000);
+
+        // Only the owner can propose
+        let err = propose_new_owner(deps.as_mut(), env.clone(), multisig.clone(), multisig.to_string(), 100).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        let res = propose_new_owner(deps.as_mut(), env.clone(), owner.clone(), multisig.to_string(), 100).unwrap();
+        assert_eq!(res.attributes, vec![
+            attr("action", "propose_new_owner"),
+            attr("old_owner", "input"),
+            attr("new_owner", "multisig"),
+            attr("expires_at", "1100"),
+        ]);
+
+        // Nobody else can accept, and the owner is unchanged until accepted
+        let err = accept_ownership(deps.as_mut(), env.clone(), Addr::unchecked("someone")).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        assert_eq!(CONFIG.load(deps.as_ref().storage).unwrap().owner, owner);
+
+        // An expired proposal cannot be accepted
+        let mut late = env.clone();
+        late.block.time = Timestamp::from_seconds(1100);
+        let err = accept_ownership(deps.as_mut(), late, multisig.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::OwnershipProposalExpired {}));
+
+        let res = accept_ownership(deps.as_mut(), env.clone(), multisig.clone()).unwrap();
+        assert_eq!(res.attributes, vec![
+            attr("action", "accept_ownership"),
+            attr("old_owner", "input"),
+            attr("new_owner", "multisig"),
+        ]);
+        assert_eq!(CONFIG.load(deps.as_ref().storage).unwrap().owner, multisig);
+        assert!(OWNERSHIP_PROPOSAL.may_load(deps.as_ref().storage).unwrap().is_none());
+
+        // The old owner lost its rights, the new owner can cancel its own proposals
+        let err = propose_new_owner(deps.as_mut(), env.clone(), owner.clone(), owner.to_string(), 100).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        propose_new_owner(deps.as_mut(), env.clone(), multisig.clone(), owner.to_string(), 100).unwrap();
+        cancel_ownership_proposal(deps.as_mut(), multisig.clone()).unwrap();
+        let err = accept_ownership(deps.as_mut(), env, owner).unwrap_err();
+        assert!(matches!(err, ContractError::NoOwnershipProposal {}));
+    }
+
+    #[test]
+    fn test_roles_are_managed_by_owner() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let owner = Addr::unchecked("input");
+        let backend = Addr::unchecked("backend");
+
+        let err = grant_role(deps.as_mut(), backend.clone(), Role::Operator, backend.to_string()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        grant_role(deps.as_mut(), owner.clone(), Role::Operator, backend.to_string()).unwrap();
+        assert!(assert_role(deps.as_ref().storage, Role::Operator, &backend).is_ok());
+
+        // Roles are independent of each other
+        let err = assert_role(deps.as_ref().storage, Role::Guardian, &backend).unwrap_err();
+        assert!(matches!(err, ContractError::MissingRole { role } if role == "guardian"));
+
+        revoke_role(deps.as_mut(), owner, Role::Operator, backend.to_string()).unwrap();
+        assert!(assert_role(deps.as_ref().storage, Role::Operator, &backend).is_err());
+    }
+
+    #[test]
+    fn test_repay_collects_fee_for_fee_collector() {
+        let mut deps = mock_dependencies();
+        let borrower = setup_open_loan(deps.as_mut(), 1000);
+        let config = Config { fee_percentage: Decimal::percent(20), ..mock_config() };
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+
+        // A year at 10% is 100 interest, 20 of it is the protocol fee
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(SECONDS_PER_YEAR);
+        repay_loan(deps.as_mut(), env, borrower, 1, Uint128::new(1100)).unwrap();
+
+        assert_eq!(COLLECTED_FEES.load(deps.as_ref().storage).unwrap(), Uint128::new(20));
+        assert_eq!(VAULT.load(deps.as_ref().storage).unwrap().total_tokens, Uint128::new(1080));
+
+        // Only the fee collector can withdraw the fees
+        let collector = Addr::unchecked("collector");
+        let err = withdraw_fees(deps.as_mut(), collector.clone(), None).unwrap_err();
+        assert!(matches!(err, ContractError::MissingRole { .. }));
+
+        grant_role(deps.as_mut(), Addr::unchecked("input"), Role::FeeCollector, collector.to_string()).unwrap();
+        let err = withdraw_fees(deps.as_mut(), collector.clone(), Some(Uint128::new(21))).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficientFunds {}));
+
+        let res = withdraw_fees(deps.as_mut(), collector.clone(), None).unwrap();
+        assert_eq!(
+            res.messages[0].msg,
+            transfer_cw20(&Addr::unchecked("token_address"), &collector, Uint128::new(20)).unwrap()
+        );
+        assert_eq!(COLLECTED_FEES.load(deps.as_ref().storage).unwrap(), Uint128::zero());
+    }
+
+    #[test]
+    fn test_settle_backend_trade_from_escrow() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let owner = Addr::unchecked("input");
+        let operator = Addr::unchecked("operator");
+        let lender = Addr::unchecked("lender");
+        execute_escrow(deps.as_mut(), mock_env(), lender.clone(), Addr::unchecked("token_address"), Uint128::new(100), 0).unwrap();
+        let env = mock_env();
+        let short = open_maturity(deps.as_mut().storage, &env, 60, 0);
+        let year = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+
+        let err = settle_backend_trade(deps.as_mut(), mock_env(), operator.clone(), lender.to_string(), Uint128::new(100), short, TradeBacking::Escrow { escrow_id: 1 }, None).unwrap_err();
+        assert!(matches!(err, ContractError::MissingRole { .. }));
+        grant_role(deps.as_mut(), owner, Role::Operator, operator.to_string()).unwrap();
+
+        // More than the escrow holds fails without touching the vault
+        let err = settle_backend_trade(deps.as_mut(), mock_env(), operator.clone(), lender.to_string(), Uint128::new(101), short, TradeBacking::Escrow { escrow_id: 1 }, None).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficientBacking { .. }));
+        assert!(VAULT.may_load(deps.as_ref().storage).unwrap().is_none());
+
+        // The operator can only pass on a rate the backend signed for this lender and trade
+        set_backend_pubkey(deps.as_mut(), Addr::unchecked("input"), Some(backend_pubkey())).unwrap();
+        let expires_at = env.block.time.seconds() + 60;
+        let payload = quote(QuoteAction::Lend, "operator", 100, year, Decimal::percent(50), 1, expires_at);
+        let err = settle_backend_trade(deps.as_mut(), mock_env(), operator.clone(), lender.to_string(), Uint128::new(100), year, TradeBacking::Escrow { escrow_id: 1 }, Some(sign_quote(&payload))).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteMismatch {}));
+        let mut forged = sign_quote(&quote(QuoteAction::Lend, lender.as_str(), 100, year, Decimal::percent(8), 2, expires_at));
+        forged.payload = to_binary(&quote(QuoteAction::Lend, lender.as_str(), 100, year, Decimal::percent(50), 2, expires_at)).unwrap();
+        let err = settle_backend_trade(deps.as_mut(), mock_env(), operator.clone(), lender.to_string(), Uint128::new(100), year, TradeBacking::Escrow { escrow_id: 1 }, Some(forged)).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidQuoteSignature {}));
+
+        // The backend's quote overrides the configured term rate
+        let payload = quote(QuoteAction::Lend, lender.as_str(), 100, year, Decimal::percent(8), 3, expires_at);
+        let res = settle_backend_trade(deps.as_mut(), mock_env(), operator.clone(), lender.to_string(), Uint128::new(100), year, TradeBacking::Escrow { escrow_id: 1 }, Some(sign_quote(&payload))).unwrap();
+        assert!(res.messages.is_empty());
+        assert!(res.attributes.contains(&attr("quote_nonce", "3")));
+        assert!(!ESCROW.has(deps.as_ref().storage, (&lender, 1)));
+        assert_eq!(VAULT.load(deps.as_ref().storage).unwrap().total_tokens, Uint128::new(100));
+        let position = LENDERS.load(deps.as_ref().storage, (&lender, 1)).unwrap();
+        assert_eq!(position.amount_lent, Uint128::new(100));
+        assert_eq!(position.rate, Decimal::percent(8));
+        assert_eq!(position.amount_at_maturity, Uint128::new(108));
+    }
+
+    #[test]
+    fn test_collateral_withdrawal_keeps_loans_covered() {
+        let mut deps = mock_dependencies();
+        let borrower = setup_open_loan(deps.as_mut(), 300);
+        let owner = Addr::unchecked("input");
+        let atom = Addr::unchecked("atom");
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(0);
+
+        let params = CollateralAsset {
+            price: Decimal::percent(200),
+            max_ltv: Decimal::percent(75),
+            liquidation_threshold: Decimal::percent(85),
+        };
+        let err = set_collateral_asset(deps.as_mut(), borrower.clone(), atom.to_string(), params.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        // The max LTV has to stay below the liquidation threshold
+        let inverted = CollateralAsset { max_ltv: Decimal::percent(90), ..params.clone() };
+        let err = set_collateral_asset(deps.as_mut(), owner.clone(), atom.to_string(), inverted).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidConfig { .. }));
+        set_collateral_asset(deps.as_mut(), owner, atom.to_string(), params).unwrap();
+
+        let err = assert_accepted_token(deps.as_ref().storage, &Addr::unchecked("osmo"), &Cw20HookMsg::Collateral {}).unwrap_err();
+        assert!(matches!(err, ContractError::UnacceptedToken { .. }));
+        assert_accepted_token(deps.as_ref().storage, &atom, &Cw20HookMsg::Collateral {}).unwrap();
+        deposit_collateral(deps.as_mut(), borrower.clone(), atom.clone(), Uint128::new(100)).unwrap();
+        deposit_collateral(deps.as_mut(), borrower.clone(), atom.clone(), Uint128::new(200)).unwrap();
+        assert_eq!(COLLATERALS.load(deps.as_ref().storage, (&borrower, &atom)).unwrap(), Uint128::new(300));
+
+        // 300 atom at a price of 2 and a 75% LTV back 450, 150 atom would only back 225
+        let health = account_health(deps.as_ref(), env.block.time.seconds(), &borrower, Decimal256::one()).unwrap();
+        assert_eq!(health.borrow_capacity, Uint128::new(450));
+        let err = withdraw_collateral(deps.as_mut(), env.clone(), borrower.clone(), atom.to_string(), Uint128::new(150)).unwrap_err();
+        assert!(matches!(err, ContractError::Undercollateralized { debt, borrow_capacity }
+            if debt == Uint128::new(300) && borrow_capacity == Uint128::new(225)));
+        assert_eq!(COLLATERALS.load(deps.as_ref().storage, (&borrower, &atom)).unwrap(), Uint128::new(300));
+
+        let res = withdraw_collateral(deps.as_mut(), env.clone(), borrower.clone(), atom.to_string(), Uint128::new(50)).unwrap();
+        assert_eq!(res.messages[0].msg, transfer_cw20(&atom, &borrower, Uint128::new(50)).unwrap());
+
+        // Once the loan is repaid everything can be withdrawn
+        repay_loan(deps.as_mut(), env.clone(), borrower.clone(), 1, Uint128::new(300)).unwrap();
+        let err = withdraw_collateral(deps.as_mut(), env.clone(), borrower.clone(), atom.to_string(), Uint128::new(251)).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficientFunds {}));
+        withdraw_collateral(deps.as_mut(), env, borrower.clone(), atom.to_string(), Uint128::new(250)).unwrap();
+        assert!(!COLLATERALS.has(deps.as_ref().storage, (&borrower, &atom)));
+    }
+
+    #[test]
+    fn test_pause_blocks_actions() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let owner = Addr::unchecked("input");
+        let guardian = Addr::unchecked("guardian");
+        let lender = Addr::unchecked("lender");
+
+        let err = pause(deps.as_mut(), guardian.clone(), None).unwrap_err();
+        assert!(matches!(err, ContractError::MissingRole { .. }));
+        grant_role(deps.as_mut(), owner.clone(), Role::Guardian, guardian.to_string()).unwrap();
+
+        let maturity_date = open_maturity(deps.as_mut().storage, &mock_env(), 60, 0);
+        post_collateral(deps.as_mut().storage, &lender, 100);
+
+        // Pausing borrows leaves lending open
+        pause(deps.as_mut(), guardian.clone(), Some(PausableAction::Borrow)).unwrap();
+        lend_to_pool(deps.as_mut(), mock_env(), lender.clone(), Uint128::new(100), maturity_date).unwrap();
+        let err = borrow_from_pool(deps.as_mut(), mock_env(), lender.clone(), Uint128::new(10), maturity_date).unwrap_err();
+        assert!(matches!(err, ContractError::Paused { action } if action == "borrow"));
+
+        // The global flag stops everything
+        pause(deps.as_mut(), guardian.clone(), None).unwrap();
+        let err = lend_to_pool(deps.as_mut(), mock_env(), lender.clone(), Uint128::new(100), maturity_date).unwrap_err();
+        assert!(matches!(err, ContractError::Paused { action } if action == "lend"));
+        let err = earn_tokens_into_pool(deps.as_mut(), lender.clone(), Uint128::new(100)).unwrap_err();
+        assert!(matches!(err, ContractError::Paused { action } if action == "earn"));
+        let mut later = mock_env();
+        later.block.time = later.block.time.plus_seconds(60);
+        let err = settle_maturity(deps.as_mut(), later, maturity_date).unwrap_err();
+        assert!(matches!(err, ContractError::Paused { action } if action == "withdraw"));
+
+        // Guardians can not unpause
+        let err = unpause(deps.as_mut(), guardian, None).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        unpause(deps.as_mut(), owner.clone(), None).unwrap();
+        lend_to_pool(deps.as_mut(), mock_env(), lender.clone(), Uint128::new(100), maturity_date).unwrap();
+        assert!(borrow_from_pool(deps.as_mut(), mock_env(), lender.clone(), Uint128::new(10), maturity_date).is_err());
+
+        unpause(deps.as_mut(), owner, Some(PausableAction::Borrow)).unwrap();
+        borrow_from_pool(deps.as_mut(), mock_env(), lender, Uint128::new(10), maturity_date).unwrap();
+        assert_eq!(PAUSE_STATE.load(deps.as_ref().storage).unwrap(), Default::default());
+    }
+
+    #[test]
+    fn test_borrow_rate_follows_utilization() {
+        let mut deps = mock_dependencies();
+        let config = Config {
+            base_interest_rate: Decimal::percent(2),
+            slope1: Decimal::percent(4),
+            slope2: Decimal::percent(60),
+            optimal_utilization: Decimal::percent(80),
+            ..mock_config()
+        };
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        VAULT.save(deps.as_mut().storage, &Vault { total_tokens: Uint128::new(1000), ..Vault::default() }).unwrap();
+
+        // Borrowing 900 of 1000 puts utilization past the kink at 90%, a 36% borrow rate
+        let borrower = Addr::unchecked("borrower_address");
+        let mut env = mock_env();
+        env.block.time = Timestamp::from_seconds(0);
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 1000);
+        post_collateral(deps.as_mut().storage, &borrower, 1800);
+        borrow_from_pool(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(900), maturity_date).unwrap();
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(vault.total_tokens, Uint128::new(100));
+        assert_eq!(vault.total_borrowed, Uint128::new(900));
+
+        env.block.time = Timestamp::from_seconds(SECONDS_PER_YEAR);
+        let res = repay_loan(deps.as_mut(), env, borrower, 1, Uint128::new(100)).unwrap();
+        assert!(res.attributes.contains(&attr("interest_paid", "100")));
+        assert!(res.attributes.contains(&attr("remaining_debt", "1124")));
+    }
+
+    #[test]
+    fn test_lenders_lock_the_term_rate_of_their_tier() {
+        let mut deps = mock_dependencies();
+        let config = Config {
+            term_rates: vec![
+                TermRate { duration: 0, rate: Decimal::percent(4) },
+                TermRate { duration: SECONDS_PER_YEAR / 2, rate: Decimal::percent(8) },
+                TermRate { duration: SECONDS_PER_YEAR, rate: Decimal::percent(10) },
+            ],
+            ..mock_config()
+        };
+        CONFIG.save(deps.as_mut().storage, &config).unwrap();
+        let lender = Addr::unchecked("lender_address");
+
+        // Three quarters of a year falls in the half year tier
+        let maturity_date = open_maturity(deps.as_mut().storage, &mock_env(), SECONDS_PER_YEAR / 4 * 3, 0);
+        lend_to_pool(deps.as_mut(), mock_env(), lender.clone(), Uint128::new(1000), maturity_date).unwrap();
+        let position = LENDERS.load(deps.as_ref().storage, (&lender, 1)).unwrap();
+        assert_eq!(position.rate, Decimal::percent(8));
+        assert_eq!(position.amount_at_maturity, Uint128::new(1060));
+
+        // Later changes to the tiers do not touch open positions
+        let update = ConfigUpdate {
+            term_rates: Some(vec![TermRate { duration: 0, rate: Decimal::percent(1) }]),
+            ..ConfigUpdate::default()
+        };
+        let res = update_config(deps.as_mut(), Addr::unchecked("input"), update).unwrap();
+        assert!(res.attributes.contains(&attr("new_term_rates", "0:0.01")));
+        assert_eq!(LENDERS.load(deps.as_ref().storage, (&lender, 1)).unwrap(), position);
+
+        // Tiers must cover the shortest duration and stay sorted
+        let config = Config { term_rates: vec![], ..mock_config() };
+        assert!(matches!(validate_config(&config), Err(ContractError::InvalidConfig { .. })));
+        let config = Config {
+            term_rates: vec![
+                TermRate { duration: 0, rate: Decimal::percent(4) },
+                TermRate { duration: 0, rate: Decimal::percent(8) },
+            ],
+            ..mock_config()
+        };
+        assert!(matches!(validate_config(&config), Err(ContractError::InvalidConfig { .. })));
+    }
+
+    fn quote(action: QuoteAction, account: &str, amount: u128, maturity_date: u64, rate: Decimal, nonce: u64, expires_at: u64) -> QuotePayload {
+        QuotePayload {
+            action,
+            account: account.to_string(),
+            amount: Uint128::new(amount),
+            maturity_date,
+            rate,
+            nonce,
+            expires_at,
+            contract: mock_env().contract.address.to_string(),
+            chain_id: mock_env().block.chain_id,
+        }
+    }
+
+    #[test]
+    fn test_quoted_lends_lock_the_backend_rate() {
+        let mut deps = mock_dependencies();
+        CONFIG.save(deps.as_mut().storage, &mock_config()).unwrap();
+        let env = mock_env();
+        let now = env.block.time.seconds();
+        let lender = Addr::unchecked("lender_address");
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 0);
+        let payload = quote(QuoteAction::Lend, lender.as_str(), 1000, maturity_date, Decimal::percent(8), 1, now + 60);
+
+        // Quotes can not be checked before the owner sets the backend's key
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, sign_quote(&payload)).unwrap_err();
+        assert!(matches!(err, ContractError::NoBackendKey {}));
+        let err = set_backend_pubkey(deps.as_mut(), Addr::unchecked("input"), Some(Binary::from(vec![2; 32]))).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidConfig { .. }));
+        let err = set_backend_pubkey(deps.as_mut(), lender.clone(), Some(backend_pubkey())).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let res = set_backend_pubkey(deps.as_mut(), Addr::unchecked("input"), Some(backend_pubkey())).unwrap();
+        assert!(res.attributes.contains(&attr("old_backend_pubkey", "none")));
+        assert!(res.attributes.contains(&attr("new_backend_pubkey", backend_pubkey().to_base64())));
+
+        // A quote for another deployment can not be replayed here
+        let replayed = sign_quote(&QuotePayload { contract: "other_contract".to_string(), ..payload.clone() });
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, replayed).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteWrongDeployment {}));
+        let replayed = sign_quote(&QuotePayload { chain_id: "other-chain".to_string(), ..payload.clone() });
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, replayed).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteWrongDeployment {}));
+
+        // The quoted 8% replaces the 10% term rate
+        let res = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, sign_quote(&payload)).unwrap();
+        assert!(res.attributes.contains(&attr("quote_nonce", "1")));
+        let position = LENDERS.load(deps.as_ref().storage, (&lender, 1)).unwrap();
+        assert_eq!(position.rate, Decimal::percent(8));
+        assert_eq!(position.amount_at_maturity, Uint128::new(1080));
+
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, sign_quote(&payload)).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteNonceUsed { nonce: 1 }));
+
+        // A quote only covers the amount it was made for
+        let payload = QuotePayload { nonce: 2, ..payload };
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(999), maturity_date, sign_quote(&payload)).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteMismatch {}));
+
+        // Changing the payload after signing breaks the signature
+        let mut tampered = sign_quote(&QuotePayload { nonce: 3, ..payload.clone() });
+        tampered.payload = to_binary(&QuotePayload { nonce: 3, rate: Decimal::percent(20), ..payload.clone() }).unwrap();
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, tampered).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidQuoteSignature {}));
+
+        let expired = sign_quote(&QuotePayload { nonce: 4, expires_at: now - 1, ..payload.clone() });
+        let err = lend_with_quote(deps.as_mut(), env.clone(), lender.clone(), Uint128::new(1000), maturity_date, expired).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteExpired { .. }));
+
+        // Clearing the key stops quotes from being accepted
+        let res = set_backend_pubkey(deps.as_mut(), Addr::unchecked("input"), None).unwrap();
+        assert!(res.attributes.contains(&attr("new_backend_pubkey", "none")));
+        let quote = sign_quote(&QuotePayload { nonce: 5, ..payload });
+        let err = lend_with_quote(deps.as_mut(), env, lender, Uint128::new(1000), maturity_date, quote).unwrap_err();
+        assert!(matches!(err, ContractError::NoBackendKey {}));
+    }
+
+    #[test]
+    fn test_quoted_borrows_owe_fixed_term_interest() {
+        let mut deps = mock_dependencies();
+        VAULT.save(deps.as_mut().storage, &Vault { total_tokens: Uint128::new(1000), ..Vault::default() }).unwrap();
+        CONFIG.save(deps.as_mut().storage, &Config { backend_pubkey: Some(backend_pubkey()), ..mock_config() }).unwrap();
+        let mut env = mock_env();
+        let now = env.block.time.seconds();
+        let borrower = Addr::unchecked("borrower_address");
+        let maturity_date = open_maturity(deps.as_mut().storage, &env, SECONDS_PER_YEAR, 1000);
+        post_collateral(deps.as_mut().storage, &borrower, 1000);
+
+        // 400 at 5% for a year owes 20 of interest from the start
+        let payload = quote(QuoteAction::Borrow, borrower.as_str(), 400, maturity_date, Decimal::percent(5), 1, now + 60);
+        let res = borrow_with_quote(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(400), maturity_date, sign_quote(&payload)).unwrap();
+        assert!(res.attributes.contains(&attr("quote_nonce", "1")));
+        let loan = BORROWERS.load(deps.as_ref().storage, (&borrower, 1)).unwrap();
+        assert_eq!(loan.fixed_rate, Some(Decimal::percent(5)));
+        assert_eq!(loan.interest_accrued, Uint128::new(20));
+
+        // A lend quote can not be used to borrow
+        let payload = quote(QuoteAction::Lend, borrower.as_str(), 50, maturity_date, Decimal::percent(5), 2, now + 60);
+        let err = borrow_with_quote(deps.as_mut(), env.clone(), borrower.clone(), Uint128::new(50), maturity_date, sign_quote(&payload)).unwrap_err();
+        assert!(matches!(err, ContractError::QuoteMismatch {}));
+
+        // The borrow index moves on at 10%, the fixed-rate debt stays at 420
+        env.block.time = env.block.time.plus_seconds(SECONDS_PER_YEAR / 2);
+        let res = repay_loan(deps.as_mut(), env, borrower.clone(), 1, Uint128::new(420)).unwrap();
+        assert!(res.attributes.contains(&attr("interest_paid", "20")));
+        assert!(res.attributes.contains(&attr("remaining_debt", "0")));
+        assert_eq!(BORROWERS.load(deps.as_ref().storage, (&borrower, 1)).unwrap().status, LoanStatus::Repaid);
     }
 }
@@ -0,0 +1,106 @@
+use cosmwasm_std::{Addr, Decimal, Decimal256, Deps, Order, StdResult, Uint128};
+
+use crate::error::ContractError;
+use crate::oracle::load_collateral_asset;
+use crate::state::{CollateralAsset, BORROWERS, COLLATERALS};
+
+// A borrower's collateral and debt, everything valued in pool tokens
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AccountHealth {
+    pub collateral_value: Uint128,
+    // debt the collateral can back at each asset's max LTV
+    pub borrow_capacity: Uint128,
+    // collateral value weighted by each asset's liquidation threshold
+    pub liquidation_value: Uint128,
+    pub debt: Uint128,
+}
+
+impl AccountHealth {
+    pub fn add_collateral(&mut self, asset: &CollateralAsset, amount: Uint128) -> StdResult<()> {
+        let value = asset.value(amount)?;
+        self.collateral_value += value;
+        self.borrow_capacity += value * asset.max_ltv;
+        self.liquidation_value += value * asset.liquidation_threshold;
+        Ok(())
+    }
+
+    pub fn remove_collateral(&mut self, asset: &CollateralAsset, amount: Uint128) -> StdResult<()> {
+        let value = asset.value(amount)?;
+        self.collateral_value = self.collateral_value.checked_sub(value)?;
+        self.borrow_capacity = self.borrow_capacity.checked_sub(value * asset.max_ltv)?;
+        self.liquidation_value = self.liquidation_value.checked_sub(value * asset.liquidation_threshold)?;
+        Ok(())
+    }
+
+    // Liquidation value over debt, the account can be liquidated below one. `None` without debt
+    pub fn health_factor(&self) -> Option<Decimal> {
+        if self.debt.is_zero() {
+            return None;
+        }
+        Some(Decimal::checked_from_ratio(self.liquidation_value, self.debt).unwrap_or(Decimal::MAX))
+    }
+
+    // Borrows are held to the max LTV, which keeps the health factor above one
+    pub fn is_within_capacity(&self) -> bool {
+        self.debt <= self.borrow_capacity
+    }
+
+    pub fn remaining_capacity(&self) -> Uint128 {
+        self.borrow_capacity.saturating_sub(self.debt)
+    }
+}
+
+// Values every collateral the borrower holds at the prices of `now` and their open loans at `borrow_index`
+pub fn account_health(deps: Deps, now: u64, borrower: &Addr, borrow_index: Decimal256) -> Result<AccountHealth, ContractError> {
+    let mut health = AccountHealth::default();
+
+    for item in COLLATERALS.prefix(borrower).range(deps.storage, None, None, Order::Ascending) {
+        let (asset, amount) = item?;
+        // Deregistered assets no longer back anything
+        if let Some(params) = load_collateral_asset(deps, &asset, now)? {
+            health.add_collateral(&params, amount)?;
+        }
+    }
+
+    for item in BORROWERS.prefix(borrower).range(deps.storage, None, None, Order::Ascending) {
+        let (_, loan) = item?;
+        if loan.status.is_outstanding() {
+            health.debt += loan.debt_at(borrow_index)?;
+        }
+    }
+
+    Ok(health)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn atom() -> CollateralAsset {
+        CollateralAsset {
+            price: Decimal::percent(200),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        }
+    }
+
+    #[test]
+    fn health_factor_weights_collateral_by_threshold() {
+        let mut health = AccountHealth::default();
+        health.add_collateral(&atom(), Uint128::new(100)).unwrap();
+        assert_eq!(health.collateral_value, Uint128::new(200));
+        assert_eq!(health.borrow_capacity, Uint128::new(100));
+        assert_eq!(health.liquidation_value, Uint128::new(160));
+        assert_eq!(health.health_factor(), None);
+
+        health.debt = Uint128::new(100);
+        assert!(health.is_within_capacity());
+        assert_eq!(health.remaining_capacity(), Uint128::zero());
+        assert_eq!(health.health_factor(), Some(Decimal::percent(160)));
+
+        // Once the collateral is gone only the debt is left
+        health.remove_collateral(&atom(), Uint128::new(100)).unwrap();
+        assert!(!health.is_within_capacity());
+        assert_eq!(health.health_factor(), Some(Decimal::zero()));
+    }
+}
@@ -1,7 +1,17 @@
 #[cfg(test)]
 mod tests {
-    use cosmwasm_std::{Addr, Coin, Empty, Uint128};
-    use cw_multi_test::{App, AppBuilder, Contract, ContractWrapper, Executor};
+    use cosmwasm_std::{to_binary, Addr, Coin, Decimal, Empty, Uint128};
+    use cw20::{BalanceResponse, Cw20Coin, Cw20ExecuteMsg, Cw20QueryMsg};
+    use cw_multi_test::{App, AppBuilder, AppResponse, Contract, ContractWrapper, Executor};
+
+    use crate::msg::{
+        BorrowerPoolResponse, CollateralResponse, CollateralsResponse, ConfigUpdate, Cw20HookMsg, EarnBalanceResponse, EscrowsResponse, ExecuteMsg, HealthFactorResponse,
+        InstantiateMsg, LenderPoolResponse, QueryMsg, TradeBacking,
+    };
+    use crate::mock_oracle;
+    use crate::quote::testing::{backend_pubkey, sign_quote};
+    use crate::quote::{QuoteAction, QuotePayload};
+    use crate::state::{LoanStatus, OracleConfig, PausableAction, Role, TermRate};
 
     pub fn contract_template() -> Box<dyn Contract<Empty>> {
         let contract = ContractWrapper::new(
@@ -12,8 +22,24 @@ mod tests {
         Box::new(contract)
     }
 
-    const USER: &str = "USER";
-    const ADMIN: &str = "ADMIN";
+    pub fn oracle_template() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(mock_oracle::execute, mock_oracle::instantiate, mock_oracle::query);
+        Box::new(contract)
+    }
+
+    pub fn cw20_template() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(
+            cw20_base::contract::execute,
+            cw20_base::contract::instantiate,
+            cw20_base::contract::query,
+        );
+        Box::new(contract)
+    }
+
+    const USER: &str = "user";
+    const ADMIN: &str = "admin";
+    const LENDER: &str = "lender";
+    const BORROWER: &str = "borrower";
     const NATIVE_DENOM: &str = "denom";
 
     fn mock_app() -> App {
@@ -31,4 +57,683 @@ mod tests {
                 .unwrap();
         })
     }
+
+    // Instantiates a cw20-base token with initial balances and the pool contract using it
+    fn setup_contracts(app: &mut App, balances: &[(&str, u128)]) -> (Addr, Addr) {
+        let cw20_id = app.store_code(cw20_template());
+        let token = app
+            .instantiate_contract(
+                cw20_id,
+                Addr::unchecked(ADMIN),
+                &cw20_base::msg::InstantiateMsg {
+                    name: "Temporal Token".to_string(),
+                    symbol: "TMP".to_string(),
+                    decimals: 6,
+                    initial_balances: balances
+                        .iter()
+                        .map(|(address, amount)| Cw20Coin {
+                            address: address.to_string(),
+                            amount: Uint128::new(*amount),
+                        })
+                        .collect(),
+                    mint: None,
+                    marketing: None,
+                },
+                &[],
+                "token",
+                None,
+            )
+            .unwrap();
+
+        let code_id = app.store_code(contract_template());
+        let contract = app
+            .instantiate_contract(
+                code_id,
+                Addr::unchecked(ADMIN),
+                &InstantiateMsg {
+                    admin: None,
+                    token: token.to_string(),
+                    base_interest_rate: Decimal::percent(10),
+                    slope1: Decimal::percent(4),
+                    slope2: Decimal::percent(60),
+                    optimal_utilization: Decimal::percent(80),
+                    fee_percentage: Decimal::percent(10),
+                    min_duration: 60,
+                    max_duration: 365 * 24 * 3_600,
+                    max_borrow_amount: Uint128::new(1_000_000),
+                    close_factor: Decimal::percent(50),
+                    close_factor_dust: Uint128::new(10),
+                    liquidation_bonus: Decimal::percent(10),
+                    backend_pubkey: None,
+                    term_rates: vec![TermRate { duration: 60, rate: Decimal::percent(5) }],
+                },
+                &[],
+                "ammse",
+                None,
+            )
+            .unwrap();
+
+        (token, contract)
+    }
+
+    fn send(app: &mut App, token: &Addr, contract: &Addr, sender: &str, amount: u128, hook: &Cw20HookMsg) {
+        app.execute_contract(
+            Addr::unchecked(sender),
+            token.clone(),
+            &Cw20ExecuteMsg::Send {
+                contract: contract.to_string(),
+                amount: Uint128::new(amount),
+                msg: to_binary(hook).unwrap(),
+            },
+            &[],
+        )
+        .unwrap();
+    }
+
+    // Owner opens a maturity `duration` seconds after the current block
+    fn create_maturity(app: &mut App, contract: &Addr, duration: u64) -> u64 {
+        let maturity_date = app.block_info().time.seconds() + duration;
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &ExecuteMsg::CreateMaturity { maturity_date }, &[])
+            .unwrap();
+        maturity_date
+    }
+
+    fn balance(app: &App, token: &Addr, address: &str) -> Uint128 {
+        let res: BalanceResponse = app
+            .wrap()
+            .query_wasm_smart(token, &Cw20QueryMsg::Balance { address: address.to_string() })
+            .unwrap();
+        res.balance
+    }
+
+    #[test]
+    fn lenders_keep_independent_positions() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(USER, 400), (ADMIN, 200)]);
+
+        for (lender, amount, duration) in [(USER, 100u128, 60u64), (ADMIN, 200, 120), (USER, 300, 180)] {
+            let maturity_date = create_maturity(&mut app, &contract, duration);
+            send(&mut app, &token, &contract, lender, amount, &Cw20HookMsg::Lend { maturity_date, quote: None });
+        }
+
+        let user_positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: USER.to_string() })
+            .unwrap();
+        let ids: Vec<u64> = user_positions.positions.iter().map(|p| p.position_id).collect();
+        assert_eq!(ids, vec![1, 3]);
+        assert_eq!(user_positions.positions[1].amount_lent, Uint128::new(300));
+        assert_eq!(user_positions.positions[1].rate, Decimal::percent(5));
+
+        let admin_positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: ADMIN.to_string() })
+            .unwrap();
+        assert_eq!(admin_positions.positions.len(), 1);
+        assert_eq!(admin_positions.positions[0].amount_lent, Uint128::new(200));
+    }
+
+    #[test]
+    fn unborrowed_maturity_returns_the_principal() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(LENDER, 1_000_000)]);
+
+        // A year at 5% is owed 1_050_000 but nobody borrows to pay the interest
+        let maturity_date = create_maturity(&mut app, &contract, 365 * 24 * 3_600);
+        send(&mut app, &token, &contract, LENDER, 1_000_000, &Cw20HookMsg::Lend { maturity_date, quote: None });
+        app.update_block(|block| block.time = block.time.plus_seconds(365 * 24 * 3_600));
+
+        let withdraw = ExecuteMsg::WithdrawMatured { position_id: 1 };
+        app.execute_contract(Addr::unchecked(LENDER), contract.clone(), &withdraw, &[]).unwrap_err();
+        let res = app
+            .execute_contract(Addr::unchecked(USER), contract.clone(), &ExecuteMsg::SettleMaturity { maturity_date }, &[])
+            .unwrap();
+        let wasm = res.events.iter().find(|event| event.ty == "wasm").unwrap();
+        assert!(wasm.attributes.iter().any(|attr| attr.key == "shortfall" && attr.value == "50000"));
+
+        // Settlement pays out what the maturity holds, here the whole principal
+        app.execute_contract(Addr::unchecked(LENDER), contract.clone(), &withdraw, &[]).unwrap();
+        assert_eq!(balance(&app, &token, LENDER), Uint128::new(1_000_000));
+        assert_eq!(balance(&app, &token, contract.as_str()), Uint128::zero());
+
+        let positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: LENDER.to_string() })
+            .unwrap();
+        assert_eq!(positions.positions[0].amount_paid, Uint128::new(1_000_000));
+        assert!(!positions.positions[0].settled);
+        app.execute_contract(Addr::unchecked(LENDER), contract.clone(), &withdraw, &[]).unwrap_err();
+    }
+
+    #[test]
+    fn earn_deposits_never_reach_lender_funds() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(LENDER, 1_000_000), (USER, 1_000_000)]);
+
+        let maturity_date = create_maturity(&mut app, &contract, 365 * 24 * 3_600);
+        send(&mut app, &token, &contract, LENDER, 1_000_000, &Cw20HookMsg::Lend { maturity_date, quote: None });
+        send(&mut app, &token, &contract, USER, 1_000_000, &Cw20HookMsg::Earn {});
+        app.update_block(|block| block.time = block.time.plus_seconds(365 * 24 * 3_600));
+
+        // No interest was collected, so the earner can take out their deposit and nothing more
+        let withdraw = ExecuteMsg::WithdrawEarn { amount: Some(Uint128::new(1_100_000)) };
+        app.execute_contract(Addr::unchecked(USER), contract.clone(), &withdraw, &[]).unwrap_err();
+        let withdraw = ExecuteMsg::WithdrawEarn { amount: None };
+        app.execute_contract(Addr::unchecked(USER), contract.clone(), &withdraw, &[]).unwrap();
+        assert_eq!(balance(&app, &token, USER), Uint128::new(1_000_000));
+
+        app.execute_contract(Addr::unchecked(USER), contract.clone(), &ExecuteMsg::SettleMaturity { maturity_date }, &[])
+            .unwrap();
+        let withdraw = ExecuteMsg::WithdrawMatured { position_id: 1 };
+        app.execute_contract(Addr::unchecked(LENDER), contract.clone(), &withdraw, &[]).unwrap();
+        assert_eq!(balance(&app, &token, LENDER), Uint128::new(1_000_000));
+    }
+
+    #[test]
+    fn backend_trades_need_token_backing() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(LENDER, 1_000)]);
+        let operator = Addr::unchecked("operator");
+        app.execute_contract(
+            Addr::unchecked(ADMIN),
+            contract.clone(),
+            &ExecuteMsg::GrantRole { role: Role::Operator, address: operator.to_string() },
+            &[],
+        )
+        .unwrap();
+
+        let maturity_date = create_maturity(&mut app, &contract, 3_600);
+
+        let settle = |backing: TradeBacking, amount: u128| ExecuteMsg::SettleBackendTrade {
+            lender: LENDER.to_string(),
+            amount: Uint128::new(amount),
+            maturity_date,
+            backing,
+            quote: None,
+        };
+
+        // Only the operator settles trades
+        app.execute_contract(Addr::unchecked(LENDER), contract.clone(), &settle(TradeBacking::Allowance {}, 100), &[])
+            .unwrap_err();
+
+        // Without an allowance nothing is recorded
+        app.execute_contract(operator.clone(), contract.clone(), &settle(TradeBacking::Allowance {}, 100), &[])
+            .unwrap_err();
+        let positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: LENDER.to_string() })
+            .unwrap_or(LenderPoolResponse { positions: vec![] });
+        assert!(positions.positions.is_empty());
+
+        app.execute_contract(
+            Addr::unchecked(LENDER),
+            token.clone(),
+            &Cw20ExecuteMsg::IncreaseAllowance { spender: contract.to_string(), amount: Uint128::new(300), expires: None },
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(operator.clone(), contract.clone(), &settle(TradeBacking::Allowance {}, 300), &[])
+            .unwrap();
+        assert_eq!(balance(&app, &token, LENDER), Uint128::new(700));
+        assert_eq!(balance(&app, &token, contract.as_str()), Uint128::new(300));
+
+        // Escrowed tokens can back a trade up to the escrowed amount
+        send(&mut app, &token, &contract, LENDER, 200, &Cw20HookMsg::Escrow { time: 0 });
+        app.execute_contract(operator.clone(), contract.clone(), &settle(TradeBacking::Escrow { escrow_id: 1 }, 250), &[])
+            .unwrap_err();
+        app.execute_contract(operator, contract.clone(), &settle(TradeBacking::Escrow { escrow_id: 1 }, 150), &[])
+            .unwrap();
+
+        let escrows: EscrowsResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::Escrow { address: LENDER.to_string() })
+            .unwrap();
+        assert_eq!(escrows.escrows[0].amount, Uint128::new(50));
+        assert_eq!(balance(&app, &token, contract.as_str()), Uint128::new(500));
+
+        let positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: LENDER.to_string() })
+            .unwrap();
+        assert_eq!(positions.positions.len(), 2);
+    }
+
+    #[test]
+    fn borrower_receives_tokens_and_repays() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(LENDER, 1_000), (BORROWER, 100)]);
+
+        // Lender funds the pool through a standard Cw20 Send
+        let maturity_date = create_maturity(&mut app, &contract, 3_600);
+        send(&mut app, &token, &contract, LENDER, 1_000, &Cw20HookMsg::Lend { maturity_date, quote: None });
+        assert_eq!(balance(&app, &token, contract.as_str()), Uint128::new(1_000));
+
+        // Borrower posts another cw20 as collateral, at par it backs half its value
+        let (collateral, _) = setup_contracts(&mut app, &[(BORROWER, 1_000)]);
+        let register = ExecuteMsg::SetCollateralAsset {
+            asset: collateral.to_string(),
+            price: Decimal::one(),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &register, &[]).unwrap();
+        send(&mut app, &collateral, &contract, BORROWER, 1_000, &Cw20HookMsg::Collateral {});
+
+        // Borrowing past the max LTV fails
+        app.execute_contract(
+            Addr::unchecked(BORROWER),
+            contract.clone(),
+            &ExecuteMsg::BorrowFromPool { amount: Uint128::new(501), maturity_date, quote: None },
+            &[],
+        )
+        .unwrap_err();
+
+        // Borrower asks for pool tokens against the collateral
+        app.execute_contract(
+            Addr::unchecked(BORROWER),
+            contract.clone(),
+            &ExecuteMsg::BorrowFromPool { amount: Uint128::new(400), maturity_date, quote: None },
+            &[],
+        )
+        .unwrap();
+        assert_eq!(balance(&app, &token, BORROWER), Uint128::new(500));
+        assert_eq!(balance(&app, &token, contract.as_str()), Uint128::new(600));
+
+        // Borrowing more than the pool holds fails and moves nothing
+        app.execute_contract(
+            Addr::unchecked(BORROWER),
+            contract.clone(),
+            &ExecuteMsg::BorrowFromPool { amount: Uint128::new(601), maturity_date, quote: None },
+            &[],
+        )
+        .unwrap_err();
+        assert_eq!(balance(&app, &token, BORROWER), Uint128::new(500));
+
+        let loans: BorrowerPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::BorrowFromPool { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(loans.loans.len(), 1);
+        assert_eq!(loans.loans[0].principal, Uint128::new(400));
+        assert_eq!(loans.loans[0].status, LoanStatus::Open);
+
+        // Repaying in the same block owes no interest and closes the loan
+        send(&mut app, &token, &contract, BORROWER, 400, &Cw20HookMsg::Repay { loan_id: 1 });
+        assert_eq!(balance(&app, &token, contract.as_str()), Uint128::new(1_000));
+
+        let loans: BorrowerPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::BorrowFromPool { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(loans.loans[0].status, LoanStatus::Repaid);
+
+        // With the loan repaid the collateral is free again
+        let withdraw = ExecuteMsg::WithdrawCollateral { asset: collateral.to_string(), amount: Uint128::new(1_000) };
+        app.execute_contract(Addr::unchecked(BORROWER), contract.clone(), &withdraw, &[]).unwrap();
+        assert_eq!(balance(&app, &collateral, BORROWER), Uint128::new(1_000));
+    }
+
+    #[test]
+    fn cw20_send_reaches_every_hook() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(USER, 1_000)]);
+
+        let maturity_date = create_maturity(&mut app, &contract, 60);
+        // The pool token doubles as collateral at par
+        let register = ExecuteMsg::SetCollateralAsset {
+            asset: token.to_string(),
+            price: Decimal::one(),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &register, &[]).unwrap();
+
+        send(&mut app, &token, &contract, USER, 100, &Cw20HookMsg::Lend { maturity_date, quote: None });
+        send(&mut app, &token, &contract, USER, 200, &Cw20HookMsg::Earn {});
+        send(&mut app, &token, &contract, USER, 300, &Cw20HookMsg::Escrow { time: 60 });
+        send(&mut app, &token, &contract, USER, 50, &Cw20HookMsg::Collateral {});
+        assert_eq!(balance(&app, &token, USER), Uint128::new(350));
+
+        let positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: USER.to_string() })
+            .unwrap();
+        assert_eq!(positions.positions[0].amount_lent, Uint128::new(100));
+
+        let earn: EarnBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::EarnBalance { address: USER.to_string() })
+            .unwrap();
+        assert_eq!(earn.assets, Uint128::new(200));
+
+        let escrows: EscrowsResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::Escrow { address: USER.to_string() })
+            .unwrap();
+        let amounts: Vec<Uint128> = escrows.escrows.iter().map(|e| e.amount).collect();
+        assert_eq!(amounts, vec![Uint128::new(300)]);
+
+        let collaterals: CollateralsResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::Collaterals { address: USER.to_string() })
+            .unwrap();
+        assert_eq!(collaterals.collaterals, vec![CollateralResponse { asset: token.clone(), amount: Uint128::new(50) }]);
+
+        // Without open loans the collateral comes straight back
+        let withdraw = ExecuteMsg::WithdrawCollateral { asset: token.to_string(), amount: Uint128::new(50) };
+        app.execute_contract(Addr::unchecked(USER), contract.clone(), &withdraw, &[]).unwrap();
+        assert_eq!(balance(&app, &token, USER), Uint128::new(400));
+    }
+
+    #[test]
+    fn deposits_from_other_tokens_are_rejected() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(USER, 1_000)]);
+
+        // A second, real cw20 the pool was not configured with
+        let (fake_token, _) = setup_contracts(&mut app, &[(USER, 1_000)]);
+
+        let maturity_date = create_maturity(&mut app, &contract, 60);
+        for hook in [Cw20HookMsg::Lend { maturity_date, quote: None }, Cw20HookMsg::Earn {}, Cw20HookMsg::Escrow { time: 60 }] {
+            app.execute_contract(
+                Addr::unchecked(USER),
+                fake_token.clone(),
+                &Cw20ExecuteMsg::Send {
+                    contract: contract.to_string(),
+                    amount: Uint128::new(100),
+                    msg: to_binary(&hook).unwrap(),
+                },
+                &[],
+            )
+            .unwrap_err();
+        }
+        assert_eq!(balance(&app, &fake_token, USER), Uint128::new(1_000));
+        assert_eq!(balance(&app, &token, USER), Uint128::new(1_000));
+
+        let positions: Result<LenderPoolResponse, _> = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: USER.to_string() });
+        assert!(positions.is_err());
+    }
+
+    // Lender funds a maturity, the borrower takes 400 against 1_000 of a second token worth
+    // one pool token each, and the user approves the pool to pull repayments for liquidations
+    fn open_collateralized_loan(app: &mut App) -> (Addr, Addr, Addr) {
+        let (token, contract) = setup_contracts(app, &[(LENDER, 1_000), (USER, 1_000)]);
+        let (collateral, _) = setup_contracts(app, &[(BORROWER, 1_000)]);
+
+        let maturity_date = create_maturity(app, &contract, 3_600);
+        send(app, &token, &contract, LENDER, 1_000, &Cw20HookMsg::Lend { maturity_date, quote: None });
+        set_collateral_price(app, &contract, &collateral, Decimal::one());
+        send(app, &collateral, &contract, BORROWER, 1_000, &Cw20HookMsg::Collateral {});
+        app.execute_contract(
+            Addr::unchecked(BORROWER),
+            contract.clone(),
+            &ExecuteMsg::BorrowFromPool { amount: Uint128::new(400), maturity_date, quote: None },
+            &[],
+        )
+        .unwrap();
+
+        app.execute_contract(
+            Addr::unchecked(USER),
+            token.clone(),
+            &Cw20ExecuteMsg::IncreaseAllowance { spender: contract.to_string(), amount: Uint128::new(1_000), expires: None },
+            &[],
+        )
+        .unwrap();
+
+        (token, collateral, contract)
+    }
+
+    fn set_collateral_price(app: &mut App, contract: &Addr, collateral: &Addr, price: Decimal) {
+        let msg = ExecuteMsg::SetCollateralAsset {
+            asset: collateral.to_string(),
+            price,
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &msg, &[]).unwrap();
+    }
+
+    // Liquidates the borrower's loan as the user, failures come back as the contract's error message
+    fn liquidate(app: &mut App, contract: &Addr, repay_amount: u128) -> Result<AppResponse, String> {
+        let msg = ExecuteMsg::Liquidate {
+            borrower: BORROWER.to_string(),
+            loan_id: 1,
+            repay_amount: Uint128::new(repay_amount),
+        };
+        app.execute_contract(Addr::unchecked(USER), contract.clone(), &msg, &[])
+            .map_err(|err| err.root_cause().to_string())
+    }
+
+    // Value of `key` on the first event of type `ty` the pool emitted
+    fn event_attribute(res: &AppResponse, ty: &str, key: &str) -> String {
+        let event = res.events.iter().find(|event| event.ty == format!("wasm-{}", ty)).unwrap();
+        event.attributes.iter().find(|attr| attr.key == key).unwrap().value.clone()
+    }
+
+    #[test]
+    fn undercollateralized_loans_are_liquidated() {
+        let mut app = mock_app();
+        let (token, collateral, contract) = open_collateralized_loan(&mut app);
+
+        // A healthy loan before its maturity can not be liquidated
+        let err = liquidate(&mut app, &contract, 100).unwrap_err();
+        assert_eq!(err, "Loan is healthy and not overdue");
+
+        // The collateral drops to 0.45, 80% of its 450 value no longer covers the 400 debt
+        set_collateral_price(&mut app, &contract, &collateral, Decimal::percent(45));
+        let health: HealthFactorResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::HealthFactor { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(health.health_factor, Some(Decimal::percent(90)));
+
+        // The close factor caps the repayment at half the debt, the liquidator receives
+        // collateral worth 220 with the 10% bonus, 488 units at 0.45
+        let res = liquidate(&mut app, &contract, 300).unwrap();
+        assert_eq!(event_attribute(&res, "liquidation", "reason"), "undercollateralized");
+        assert_eq!(event_attribute(&res, "liquidation", "repaid"), "200");
+        assert_eq!(event_attribute(&res, "liquidation", "remaining_debt"), "200");
+        assert_eq!(event_attribute(&res, "collateral_seized", "amount"), "488");
+
+        assert_eq!(balance(&app, &token, USER), Uint128::new(800));
+        assert_eq!(balance(&app, &collateral, USER), Uint128::new(488));
+        let left: CollateralResponse = app
+            .wrap()
+            .query_wasm_smart(
+                &contract,
+                &QueryMsg::Collateral { address: BORROWER.to_string(), asset: collateral.to_string() },
+            )
+            .unwrap();
+        assert_eq!(left.amount, Uint128::new(512));
+
+        let loans: BorrowerPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::BorrowFromPool { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(loans.loans[0].principal, Uint128::new(200));
+        assert_eq!(loans.loans[0].status, LoanStatus::Open);
+    }
+
+    #[test]
+    fn overdue_loans_are_liquidated() {
+        let mut app = mock_app();
+        let (token, collateral, contract) = open_collateralized_loan(&mut app);
+
+        // Past its maturity an unpaid loan is open to liquidation however healthy it is
+        app.update_block(|block| block.time = block.time.plus_seconds(3_601));
+        let res = liquidate(&mut app, &contract, 1_000).unwrap();
+        assert_eq!(event_attribute(&res, "liquidation", "reason"), "overdue");
+
+        // The hour of interest rounds the debt up to 401, half of it is repaid
+        assert_eq!(event_attribute(&res, "liquidation", "repaid"), "200");
+        assert_eq!(event_attribute(&res, "liquidation", "remaining_debt"), "201");
+        assert_eq!(balance(&app, &token, USER), Uint128::new(800));
+        assert_eq!(balance(&app, &collateral, USER), Uint128::new(220));
+
+        // The loan is marked defaulted and the rest of its debt stays open to liquidation
+        let loans: BorrowerPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::BorrowFromPool { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(loans.loans[0].status, LoanStatus::Defaulted);
+        let res = liquidate(&mut app, &contract, 100).unwrap();
+        assert_eq!(event_attribute(&res, "liquidation", "remaining_debt"), "101");
+        let loans: BorrowerPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::BorrowFromPool { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(loans.loans[0].status, LoanStatus::Defaulted);
+
+        // Liquidations can be paused by a guardian
+        let guardian = Addr::unchecked("guardian");
+        let grant = ExecuteMsg::GrantRole { role: Role::Guardian, address: guardian.to_string() };
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &grant, &[]).unwrap();
+        let pause = ExecuteMsg::Pause { action: Some(PausableAction::Liquidate) };
+        app.execute_contract(guardian, contract.clone(), &pause, &[]).unwrap();
+        let err = liquidate(&mut app, &contract, 100).unwrap_err();
+        assert_eq!(err, "Paused: liquidate");
+    }
+
+    #[test]
+    fn liquidations_are_capped_by_the_collateral_left() {
+        let mut app = mock_app();
+        let (token, collateral, contract) = open_collateralized_loan(&mut app);
+
+        // At 0.2 the collateral is worth 200, it covers a repayment of 181 with the 10% bonus
+        // even though the close factor would allow 200
+        set_collateral_price(&mut app, &contract, &collateral, Decimal::percent(20));
+        let res = liquidate(&mut app, &contract, 300).unwrap();
+        assert_eq!(event_attribute(&res, "liquidation", "repaid"), "181");
+        assert_eq!(event_attribute(&res, "liquidation", "remaining_debt"), "219");
+        assert_eq!(event_attribute(&res, "collateral_seized", "amount"), "995");
+        assert_eq!(balance(&app, &token, USER), Uint128::new(819));
+        assert_eq!(balance(&app, &collateral, USER), Uint128::new(995));
+    }
+
+    #[test]
+    fn dust_debts_are_liquidated_in_full() {
+        let mut app = mock_app();
+        let (token, collateral, contract) = open_collateralized_loan(&mut app);
+        let update = ConfigUpdate { close_factor_dust: Some(Uint128::new(400)), ..ConfigUpdate::default() };
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &ExecuteMsg::UpdateConfig(Box::new(update)), &[])
+            .unwrap();
+
+        // The whole 400 debt is at the dust threshold, the close factor does not split it
+        set_collateral_price(&mut app, &contract, &collateral, Decimal::percent(45));
+        let res = liquidate(&mut app, &contract, 1_000).unwrap();
+        assert_eq!(event_attribute(&res, "liquidation", "repaid"), "400");
+        assert_eq!(event_attribute(&res, "liquidation", "remaining_debt"), "0");
+        assert_eq!(balance(&app, &token, USER), Uint128::new(600));
+
+        let loans: BorrowerPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::BorrowFromPool { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(loans.loans[0].status, LoanStatus::Repaid);
+    }
+
+    fn set_oracle_price(app: &mut App, oracle: &Addr, collateral: &Addr, price: Decimal, ema_price: Option<Decimal>) {
+        let msg = mock_oracle::ExecuteMsg::SetPrice { asset: collateral.to_string(), price, ema_price, updated_at: None };
+        app.execute_contract(Addr::unchecked(ADMIN), oracle.clone(), &msg, &[]).unwrap();
+    }
+
+    #[test]
+    fn oracle_prices_trigger_liquidations() {
+        let mut app = mock_app();
+        let (_, collateral, contract) = open_collateralized_loan(&mut app);
+
+        let oracle_id = app.store_code(oracle_template());
+        let oracle = app
+            .instantiate_contract(oracle_id, Addr::unchecked(ADMIN), &mock_oracle::InstantiateMsg {}, &[], "oracle", None)
+            .unwrap();
+        set_oracle_price(&mut app, &oracle, &collateral, Decimal::one(), None);
+        let msg = ExecuteMsg::SetOracle {
+            oracle: oracle.to_string(),
+            max_price_age: 600,
+            max_price_deviation: Decimal::percent(10),
+        };
+        let err = app.execute_contract(Addr::unchecked(USER), contract.clone(), &msg, &[]).unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "Unauthorized");
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &msg, &[]).unwrap();
+        let config: Option<OracleConfig> = app.wrap().query_wasm_smart(&contract, &QueryMsg::Oracle {}).unwrap();
+        assert_eq!(config.unwrap().address, oracle);
+
+        // The oracle's price replaces the registered one, 0.45 leaves the loan undercollateralized
+        set_oracle_price(&mut app, &oracle, &collateral, Decimal::percent(45), None);
+        let health: HealthFactorResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::HealthFactor { address: BORROWER.to_string() })
+            .unwrap();
+        assert_eq!(health.health_factor, Some(Decimal::percent(90)));
+
+        // A spot price a quarter below its average is refused
+        set_oracle_price(&mut app, &oracle, &collateral, Decimal::percent(45), Some(Decimal::percent(60)));
+        let err = liquidate(&mut app, &contract, 300).unwrap_err();
+        assert!(err.contains("deviates too far"), "{}", err);
+
+        set_oracle_price(&mut app, &oracle, &collateral, Decimal::percent(45), Some(Decimal::percent(50)));
+        let res = liquidate(&mut app, &contract, 300).unwrap();
+        assert_eq!(event_attribute(&res, "liquidation", "reason"), "undercollateralized");
+        assert_eq!(event_attribute(&res, "collateral_seized", "amount"), "488");
+
+        // A price older than the max age is refused as well
+        app.update_block(|block| block.time = block.time.plus_seconds(601));
+        let err = app
+            .wrap()
+            .query_wasm_smart::<HealthFactorResponse>(&contract, &QueryMsg::HealthFactor { address: BORROWER.to_string() })
+            .unwrap_err();
+        assert!(err.to_string().contains("is stale"), "{}", err);
+    }
+
+    #[test]
+    fn quoted_trades_execute_at_the_signed_rate() {
+        let mut app = mock_app();
+        let (token, contract) = setup_contracts(&mut app, &[(LENDER, 1_000)]);
+        let (collateral, _) = setup_contracts(&mut app, &[(BORROWER, 1_000)]);
+        let maturity_date = create_maturity(&mut app, &contract, 3_600);
+        let set_key = ExecuteMsg::SetBackendPubkey { pubkey: Some(backend_pubkey()) };
+        app.execute_contract(Addr::unchecked(ADMIN), contract.clone(), &set_key, &[]).unwrap();
+
+        let expires_at = app.block_info().time.seconds() + 60;
+        let lend = sign_quote(&QuotePayload {
+            action: QuoteAction::Lend,
+            account: LENDER.to_string(),
+            amount: Uint128::new(1_000),
+            maturity_date,
+            rate: Decimal::percent(12),
+            nonce: 1,
+            expires_at,
+            contract: contract.to_string(),
+            chain_id: app.block_info().chain_id,
+        });
+        send(&mut app, &token, &contract, LENDER, 1_000, &Cw20HookMsg::Lend { maturity_date, quote: Some(lend) });
+        let positions: LenderPoolResponse = app
+            .wrap()
+            .query_wasm_smart(&contract, &QueryMsg::LendToPool { address: LENDER.to_string() })
+            .unwrap();
+        assert_eq!(positions.positions[0].rate, Decimal::percent(12));
+
+        set_collateral_price(&mut app, &contract, &collateral, Decimal::one());
+        send(&mut app, &collateral, &contract, BORROWER, 1_000, &Cw20HookMsg::Collateral {});
+        let payload = QuotePayload {
+            action: QuoteAction::Borrow,
+            account: BORROWER.to_string(),
+            amount: Uint128::new(400),
+            maturity_date,
+            rate: Decimal::percent(15),
+            nonce: 2,
+            expires_at,
+            contract: contract.to_string(),
+            chain_id: app.block_info().chain_id,
+        };
+        let borrow = ExecuteMsg::BorrowFromPool { amount: Uint128::new(400), maturity_date, quote: Some(sign_quote(&payload)) };
+        app.execute_contract(Addr::unchecked(BORROWER), contract.clone(), &borrow, &[]).unwrap();
+        assert_eq!(balance(&app, &token, BORROWER), Uint128::new(400));
+
+        // Replaying the signed borrow is refused
+        let err = app.execute_contract(Addr::unchecked(BORROWER), contract.clone(), &borrow, &[]).unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "Quote nonce 2 was already used");
+    }
 }
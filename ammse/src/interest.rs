@@ -0,0 +1,165 @@
+use cosmwasm_std::{Decimal, Decimal256, StdResult, Uint128, Uint256};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{Vault, SECONDS_PER_YEAR};
+
+// Kinked utilization curve, the borrow rate climbs slowly up to the optimal
+// utilization and steeply after it to pull liquidity back into the vault
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct InterestRateModel {
+    pub base_rate: Decimal,
+    // added on top of the base rate as utilization reaches the optimum
+    pub slope1: Decimal,
+    // added on top of slope1 as utilization goes from the optimum to 100%
+    pub slope2: Decimal,
+    pub optimal_utilization: Decimal,
+}
+
+impl InterestRateModel {
+    // Annualized rate borrowers pay at the given utilization
+    pub fn borrow_rate(&self, utilization: Decimal) -> Decimal {
+        if utilization <= self.optimal_utilization {
+            return self.base_rate + self.slope1 * (utilization / self.optimal_utilization);
+        }
+        let excess = (utilization - self.optimal_utilization) / (Decimal::one() - self.optimal_utilization);
+        self.base_rate + self.slope1 + self.slope2 * excess
+    }
+
+    // Annualized rate lenders earn, the borrow interest spread over all liquidity less the reserve factor
+    pub fn supply_rate(&self, utilization: Decimal, reserve_factor: Decimal) -> Decimal {
+        self.borrow_rate(utilization) * utilization * (Decimal::one() - reserve_factor)
+    }
+}
+
+// Share of the vault's liquidity that is currently lent out to borrowers
+pub fn utilization(vault: &Vault) -> Decimal {
+    let total = vault.total_tokens + vault.total_borrowed;
+    if total.is_zero() {
+        return Decimal::zero();
+    }
+    Decimal::from_ratio(vault.total_borrowed, total)
+}
+
+// Grows a cumulative index by `rate` over `elapsed` seconds. The growth is simple within one
+// update and compounds across updates, the product is truncated so an index never overshoots
+pub fn grow_index(index: Decimal256, rate: Decimal, elapsed: u64) -> StdResult<Decimal256> {
+    let growth = Decimal256::from(rate).checked_mul(Decimal256::from_ratio(elapsed, SECONDS_PER_YEAR))?;
+    Ok(index.checked_mul(Decimal256::one().checked_add(growth)?)?)
+}
+
+// `amount` recorded at index `entry` grown to index `current`, rounded up for debts owed to the pool
+pub fn debt_at_index(amount: Uint128, entry: Decimal256, current: Decimal256) -> StdResult<Uint128> {
+    let numerator = Uint256::from(amount).checked_mul(current.atomics())?;
+    let (quotient, remainder) = (numerator.checked_div(entry.atomics())?, numerator.checked_rem(entry.atomics())?);
+    let rounded = if remainder.is_zero() { quotient } else { quotient.checked_add(Uint256::one())? };
+    Ok(rounded.try_into()?)
+}
+
+// `amount` recorded at index `entry` grown to index `current`, rounded down for claims on the pool
+pub fn claim_at_index(amount: Uint128, entry: Decimal256, current: Decimal256) -> StdResult<Uint128> {
+    let numerator = Uint256::from(amount).checked_mul(current.atomics())?;
+    Ok(numerator.checked_div(entry.atomics())?.try_into()?)
+}
+
+// Principal plus simple interest at a fixed annualized `rate` over `term` seconds, rounded down
+pub fn term_payout(amount: Uint128, rate: Decimal, term: u64) -> Uint128 {
+    amount + amount.multiply_ratio(term, SECONDS_PER_YEAR) * rate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn model() -> InterestRateModel {
+        InterestRateModel {
+            base_rate: Decimal::percent(2),
+            slope1: Decimal::percent(4),
+            slope2: Decimal::percent(60),
+            optimal_utilization: Decimal::percent(80),
+        }
+    }
+
+    #[test]
+    fn borrow_rate_follows_the_kinked_curve() {
+        let model = model();
+        assert_eq!(model.borrow_rate(Decimal::zero()), Decimal::percent(2));
+        assert_eq!(model.borrow_rate(Decimal::percent(40)), Decimal::percent(4));
+        assert_eq!(model.borrow_rate(Decimal::percent(80)), Decimal::percent(6));
+        // Halfway between the kink and full utilization adds half of slope2
+        assert_eq!(model.borrow_rate(Decimal::percent(90)), Decimal::percent(36));
+        assert_eq!(model.borrow_rate(Decimal::one()), Decimal::percent(66));
+    }
+
+    #[test]
+    fn supply_rate_is_net_of_reserve_factor() {
+        let model = model();
+        assert_eq!(model.supply_rate(Decimal::zero(), Decimal::percent(10)), Decimal::zero());
+        // 6% on the 80% that is borrowed, less a 10% reserve factor
+        assert_eq!(model.supply_rate(Decimal::percent(80), Decimal::percent(10)), Decimal::from_ratio(432u128, 10_000u128));
+        // Fully borrowed, lenders get the whole 66% less the reserve factor
+        assert_eq!(model.supply_rate(Decimal::one(), Decimal::percent(10)), Decimal::from_ratio(594u128, 1_000u128));
+    }
+
+    #[test]
+    fn utilization_of_vault() {
+        assert_eq!(utilization(&Vault::default()), Decimal::zero());
+        let vault = Vault { total_tokens: Uint128::new(250), total_borrowed: Uint128::new(750) };
+        assert_eq!(utilization(&vault), Decimal::percent(75));
+    }
+
+    #[test]
+    fn index_compounds_across_updates() {
+        let one_year = grow_index(Decimal256::one(), Decimal::percent(10), SECONDS_PER_YEAR).unwrap();
+        assert_eq!(one_year, Decimal256::percent(110));
+
+        let half = grow_index(Decimal256::one(), Decimal::percent(10), SECONDS_PER_YEAR / 2).unwrap();
+        let twice = grow_index(half, Decimal::percent(10), SECONDS_PER_YEAR / 2).unwrap();
+        assert_eq!(twice, Decimal256::from_ratio(11025u128, 10_000u128));
+    }
+
+    #[test]
+    fn positions_round_in_favour_of_the_pool() {
+        let entry = Decimal256::one();
+        let current = Decimal256::from_ratio(4u128, 3u128);
+        assert_eq!(debt_at_index(Uint128::new(100), entry, current).unwrap(), Uint128::new(134));
+        assert_eq!(claim_at_index(Uint128::new(100), entry, current).unwrap(), Uint128::new(133));
+    }
+
+    fn index() -> impl Strategy<Value = Decimal256> {
+        // between 1 and 100
+        (1_000_000_000_000_000_000u128..100_000_000_000_000_000_000u128)
+            .prop_map(|atomics| Decimal256::new(Uint256::from(atomics)))
+    }
+
+    proptest! {
+        #[test]
+        fn index_never_decreases(start in index(), rate in 0u64..10_000, first in 0u64..SECONDS_PER_YEAR, second in 0u64..SECONDS_PER_YEAR) {
+            let rate = Decimal::permille(rate);
+            let once = grow_index(start, rate, first).unwrap();
+            prop_assert!(once >= start);
+            prop_assert!(grow_index(once, rate, second).unwrap() >= once);
+            // Longer periods never grow less
+            prop_assert!(grow_index(start, rate, first + second).unwrap() >= once);
+        }
+
+        #[test]
+        fn debt_rounds_up_and_claims_round_down(amount in 0u128..1_000_000_000_000_000_000u128, entry in index(), growth in 0u128..10_000_000_000_000_000_000u128) {
+            let current = entry + Decimal256::new(Uint256::from(growth));
+            let amount = Uint128::new(amount);
+            let debt = debt_at_index(amount, entry, current).unwrap();
+            let claim = claim_at_index(amount, entry, current).unwrap();
+
+            // Neither side loses value as the index grows, and they differ by at most one unit
+            prop_assert!(claim >= amount);
+            prop_assert!(debt >= claim);
+            prop_assert!(debt - claim <= Uint128::one());
+
+            // The claim never exceeds and the debt never falls short of the exact value
+            let exact = Uint256::from(amount) * current.atomics();
+            prop_assert!(Uint256::from(claim) * entry.atomics() <= exact);
+            prop_assert!(Uint256::from(debt) * entry.atomics() >= exact);
+        }
+    }
+}
@@ -4,6 +4,14 @@ pub mod integration_tests;
 pub mod msg;
 pub mod state;
 pub mod execute;
+pub mod interest;
+pub mod health;
+pub mod oracle;
+pub mod quote;
+// price feed the multi-test scenarios move prices with
+#[cfg(any(test, feature = "mock-oracle"))]
+pub mod mock_oracle;
+pub mod migrate;
 use cosmwasm_std::{Storage, StdResult};
 use cw2::{ContractVersion, CONTRACT};
 
@@ -27,7 +35,6 @@ pub fn set_contract_version<T: Into<String>, U: Into<String>>(
 mod tests {
     use super::*;
     use cosmwasm_std::testing::MockStorage;
-    use cosmwasm_std::{StdError, Storage};
 
     #[test]
     fn test_set_contract_version() {
@@ -0,0 +1,363 @@
+use cosmwasm_std::{Addr, Decimal256, DepsMut, Env, Response, StdResult, Storage, Uint128};
+use cw2::{get_contract_version, set_contract_version};
+use cw_storage_plus::Item;
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::contract::{CONTRACT_NAME, CONTRACT_VERSION};
+use crate::error::ContractError;
+use crate::execute::validate_config;
+use crate::interest::term_payout;
+use crate::msg::MigrateMsg;
+use crate::state::{
+    next_escrow_id, next_lender_position_id, next_loan_id, BorrowerInfo, Config, Escrow, InterestIndex, LenderInfo, LoanStatus,
+    MaturityBucket, BORROWERS, CONFIG, EARN_POOL, EARN_SHARES, ESCROW, INTEREST_INDEX, LENDERS, MATURITIES, VAULT,
+};
+
+// A migration step upgrades the storage layout to the version it is registered under
+type MigrationStep = fn(&mut DepsMut, &Env, &MigrateMsg) -> Result<(), ContractError>;
+
+// Ordered by version, every step newer than the stored version runs once
+const MIGRATIONS: &[(&str, MigrationStep)] = &[("0.2.0", v0_2_0::migrate)];
+
+fn parse_version(version: &str) -> Result<Version, ContractError> {
+    Version::parse(version).map_err(|err| ContractError::InvalidMigration { reason: err.to_string() })
+}
+
+pub fn migrate_contract(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::InvalidMigration {
+            reason: format!("expected contract {}, found {}", CONTRACT_NAME, stored.contract),
+        });
+    }
+
+    let from = parse_version(&stored.version)?;
+    let to = parse_version(CONTRACT_VERSION)?;
+    if from > to {
+        return Err(ContractError::InvalidMigration {
+            reason: format!("cannot downgrade from {} to {}", from, to),
+        });
+    }
+
+    let mut steps = vec![];
+    for (version, step) in MIGRATIONS {
+        let version = parse_version(version)?;
+        if version > from && version <= to {
+            step(&mut deps, &env, &msg)?;
+            steps.push(version.to_string());
+        }
+    }
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", from.to_string())
+        .add_attribute("to_version", to.to_string())
+        .add_attribute("steps", steps.join(",")))
+}
+
+// 0.1.0 kept a single escrow, lender, borrower and earner in singleton items,
+// 0.2.0 keys them by address and id
+pub mod v0_2_0 {
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+    pub struct LegacyConfig {
+        pub owner: Addr,
+        pub token: Addr,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct LegacyLenderInfo {
+        pub lender: Addr,
+        pub amount_lent: Uint128,
+        pub maturity_date: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    pub struct LegacyBorrowerInfo {
+        pub borrower: Addr,
+        pub amount_borrowed: Uint128,
+        pub maturity_date: u64,
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+    pub struct LegacyEarnings {
+        pub user: Addr,
+        pub amount_supplied: Uint128,
+        pub last_updated: u64,
+    }
+
+    pub const LEGACY_CONFIG: Item<LegacyConfig> = Item::new("config");
+    pub const LEGACY_ESCROW: Item<Escrow> = Item::new("escrows:");
+    pub const LEGACY_LENDERS: Item<LegacyLenderInfo> = Item::new("lenders:");
+    pub const LEGACY_BORROWERS: Item<LegacyBorrowerInfo> = Item::new("borrowers:");
+    pub const LEGACY_EARNINGS: Item<LegacyEarnings> = Item::new("earnings");
+
+    fn required<T>(value: Option<T>, field: &str) -> Result<T, ContractError> {
+        value.ok_or_else(|| ContractError::InvalidMigration {
+            reason: format!("{} is required when migrating from 0.1.0", field),
+        })
+    }
+
+    // Legacy positions share a maturity bucket when they mature together
+    fn legacy_bucket(storage: &dyn Storage, maturity_date: u64) -> StdResult<MaturityBucket> {
+        Ok(MATURITIES.may_load(storage, maturity_date)?.unwrap_or_else(|| MaturityBucket::new(maturity_date)))
+    }
+
+    pub fn migrate(deps: &mut DepsMut, env: &Env, msg: &MigrateMsg) -> Result<(), ContractError> {
+        let now = env.block.time.seconds();
+
+        // 0.1.0 declared a config holding the owner and token but its instantiate never saved it,
+        // the migration message names them unless a deployment stored one after all
+        let (owner, token) = match LEGACY_CONFIG.may_load(deps.storage)? {
+            Some(legacy) => (legacy.owner, legacy.token),
+            None => (
+                deps.api.addr_validate(&required(msg.owner.clone(), "owner")?)?,
+                deps.api.addr_validate(&required(msg.token.clone(), "token")?)?,
+            ),
+        };
+        let update = msg.config.clone();
+        let config = Config {
+            owner,
+            token,
+            base_interest_rate: required(update.base_interest_rate, "base_interest_rate")?,
+            slope1: required(update.slope1, "slope1")?,
+            slope2: required(update.slope2, "slope2")?,
+            optimal_utilization: required(update.optimal_utilization, "optimal_utilization")?,
+            fee_percentage: required(update.fee_percentage, "fee_percentage")?,
+            min_duration: required(update.min_duration, "min_duration")?,
+            max_duration: required(update.max_duration, "max_duration")?,
+            max_borrow_amount: required(update.max_borrow_amount, "max_borrow_amount")?,
+            close_factor: required(update.close_factor, "close_factor")?,
+            close_factor_dust: update.close_factor_dust.unwrap_or_default(),
+            liquidation_bonus: required(update.liquidation_bonus, "liquidation_bonus")?,
+            // 0.1.0 had no backend, the owner sets its key with SetBackendPubkey
+            backend_pubkey: None,
+            term_rates: required(update.term_rates, "term_rates")?,
+        };
+        validate_config(&config)?;
+        CONFIG.save(deps.storage, &config)?;
+
+        // Interest indices start at one, legacy positions enter at that index
+        INTEREST_INDEX.save(deps.storage, &InterestIndex::new(now))?;
+
+        if let Some(escrow) = LEGACY_ESCROW.may_load(deps.storage)? {
+            let escrow_id = next_escrow_id(deps.storage)?;
+            ESCROW.save(deps.storage, (&escrow.user, escrow_id), &escrow)?;
+            LEGACY_ESCROW.remove(deps.storage);
+        }
+
+        // Supplied earnings become shares of the earn pool, which no longer shares the vault's tokens
+        if let Some(legacy) = LEGACY_EARNINGS.may_load(deps.storage)? {
+            let mut pool = EARN_POOL.may_load(deps.storage)?.unwrap_or_default();
+            let shares = pool.convert_to_shares(legacy.amount_supplied);
+            pool.total_assets += legacy.amount_supplied;
+            pool.total_shares += shares;
+            EARN_POOL.save(deps.storage, &pool)?;
+            EARN_SHARES.update(deps.storage, &legacy.user, |balance| -> Result<_, ContractError> {
+                Ok(balance.unwrap_or_default() + shares)
+            })?;
+            LEGACY_EARNINGS.remove(deps.storage);
+
+            let mut vault = VAULT.may_load(deps.storage)?.unwrap_or_default();
+            vault.total_tokens = vault.total_tokens.saturating_sub(legacy.amount_supplied);
+            VAULT.save(deps.storage, &vault)?;
+        }
+
+        // 0.1.0 never stored when positions started, they lock the term rate from the migration on
+        if let Some(legacy) = LEGACY_LENDERS.may_load(deps.storage)? {
+            let position_id = next_lender_position_id(deps.storage)?;
+            let start_time = now.min(legacy.maturity_date);
+            let term = legacy.maturity_date - start_time;
+            let rate = config.term_rate(term).unwrap_or_default();
+            let lender_info = LenderInfo {
+                lender: legacy.lender.clone(),
+                amount_lent: legacy.amount_lent,
+                start_time,
+                maturity_date: legacy.maturity_date,
+                rate,
+                amount_at_maturity: term_payout(legacy.amount_lent, rate, term),
+                supply_index: Decimal256::one(),
+                amount_paid: Uint128::zero(),
+                settled: false,
+            };
+            LENDERS.save(deps.storage, (&legacy.lender, position_id), &lender_info)?;
+            LEGACY_LENDERS.remove(deps.storage);
+
+            // The vault's liquidity backs the legacy maturity, as far as it reaches
+            let vault = VAULT.may_load(deps.storage)?.unwrap_or_default();
+            let mut bucket = legacy_bucket(deps.storage, legacy.maturity_date)?;
+            bucket.liquidity.total_tokens += legacy.amount_lent.min(vault.total_tokens);
+            bucket.total_principal += legacy.amount_lent;
+            bucket.total_owed += lender_info.amount_at_maturity;
+            MATURITIES.save(deps.storage, legacy.maturity_date, &bucket)?;
+        }
+
+        if let Some(legacy) = LEGACY_BORROWERS.may_load(deps.storage)? {
+            let loan_id = next_loan_id(deps.storage)?;
+            let start_time = now.min(legacy.maturity_date);
+            let loan = BorrowerInfo {
+                borrower: legacy.borrower.clone(),
+                principal: legacy.amount_borrowed,
+                interest_accrued: Uint128::zero(),
+                amount_repaid: Uint128::zero(),
+                start_time,
+                maturity_date: legacy.maturity_date,
+                borrow_index: Decimal256::one(),
+                fixed_rate: None,
+                status: LoanStatus::Open,
+            };
+            BORROWERS.save(deps.storage, (&legacy.borrower, loan_id), &loan)?;
+            LEGACY_BORROWERS.remove(deps.storage);
+
+            let mut vault = VAULT.may_load(deps.storage)?.unwrap_or_default();
+            vault.total_borrowed += legacy.amount_borrowed;
+            VAULT.save(deps.storage, &vault)?;
+
+            let mut bucket = legacy_bucket(deps.storage, legacy.maturity_date)?;
+            bucket.liquidity.total_borrowed += legacy.amount_borrowed;
+            MATURITIES.save(deps.storage, legacy.maturity_date, &bucket)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::v0_2_0::*;
+    use super::*;
+    use crate::msg::ConfigUpdate;
+    use crate::state::{TermRate, SECONDS_PER_YEAR};
+    use cosmwasm_std::testing::{mock_dependencies, mock_env};
+    use cosmwasm_std::Decimal;
+
+    fn migrate_msg() -> MigrateMsg {
+        MigrateMsg {
+            owner: Some("owner".to_string()),
+            token: Some("token".to_string()),
+            config: ConfigUpdate {
+                base_interest_rate: Some(Decimal::percent(10)),
+                slope1: Some(Decimal::percent(4)),
+                slope2: Some(Decimal::percent(60)),
+                optimal_utilization: Some(Decimal::percent(80)),
+                fee_percentage: Some(Decimal::percent(5)),
+                min_duration: Some(60),
+                max_duration: Some(SECONDS_PER_YEAR),
+                max_borrow_amount: Some(Uint128::new(1_000_000)),
+                close_factor: Some(Decimal::percent(50)),
+                close_factor_dust: None,
+                liquidation_bonus: Some(Decimal::percent(5)),
+                term_rates: Some(vec![TermRate { duration: 60, rate: Decimal::percent(5) }]),
+            },
+        }
+    }
+
+    // Storage as a 0.1.0 deployment left it, only the keys and JSON layouts 0.1.0 wrote
+    fn v0_1_0_fixture(storage: &mut dyn Storage) {
+        set_contract_version(storage, CONTRACT_NAME, "0.1.0").unwrap();
+        storage.set(b"vault", br#"{"total_tokens":"500"}"#);
+        storage.set(b"escrows:", br#"{"user":"escrower","amount":"50","time":100}"#);
+        let maturity_date = u64::MAX;
+        storage.set(b"lenders:", format!(r#"{{"lender":"lender","amount_lent":"300","maturity_date":{}}}"#, maturity_date).as_bytes());
+        storage.set(b"borrowers:", format!(r#"{{"borrower":"borrower","amount_borrowed":"200","maturity_date":{}}}"#, maturity_date).as_bytes());
+        storage.set(b"earnings", br#"{"user":"earner","amount_supplied":"100","last_updated":0}"#);
+    }
+
+    #[test]
+    fn migrates_v0_1_0_state() {
+        let mut deps = mock_dependencies();
+        v0_1_0_fixture(deps.as_mut().storage);
+        let env = mock_env();
+        let now = env.block.time.seconds();
+
+        let res = migrate_contract(deps.as_mut(), env, migrate_msg()).unwrap();
+        assert!(res.attributes.iter().any(|attr| attr.key == "steps" && attr.value == "0.2.0"));
+
+        let version = get_contract_version(deps.as_ref().storage).unwrap();
+        assert_eq!(version.version, CONTRACT_VERSION);
+
+        let config = CONFIG.load(deps.as_ref().storage).unwrap();
+        assert_eq!(config.owner, Addr::unchecked("owner"));
+        assert_eq!(config.token, Addr::unchecked("token"));
+        assert_eq!(config.fee_percentage, Decimal::percent(5));
+
+        let escrow = ESCROW.load(deps.as_ref().storage, (&Addr::unchecked("escrower"), 1)).unwrap();
+        assert_eq!(escrow.amount, Uint128::new(50));
+
+        let lender = LENDERS.load(deps.as_ref().storage, (&Addr::unchecked("lender"), 1)).unwrap();
+        assert_eq!(lender.amount_lent, Uint128::new(300));
+        assert_eq!(lender.start_time, now);
+
+        let loan = BORROWERS.load(deps.as_ref().storage, (&Addr::unchecked("borrower"), 1)).unwrap();
+        assert_eq!(loan.principal, Uint128::new(200));
+        assert_eq!(loan.status, LoanStatus::Open);
+
+        let shares = EARN_SHARES.load(deps.as_ref().storage, &Addr::unchecked("earner")).unwrap();
+        assert_eq!(shares, Uint128::new(100));
+        assert_eq!(EARN_POOL.load(deps.as_ref().storage).unwrap().total_assets, Uint128::new(100));
+
+        // The earner's tokens left the vault, which now tracks the legacy loan, and the singletons are gone
+        let vault = VAULT.load(deps.as_ref().storage).unwrap();
+        assert_eq!(vault.total_tokens, Uint128::new(400));
+        assert_eq!(vault.total_borrowed, Uint128::new(200));
+
+        // Both legacy positions mature together and share one bucket
+        let bucket = MATURITIES.load(deps.as_ref().storage, u64::MAX).unwrap();
+        assert_eq!(bucket.liquidity.total_tokens, Uint128::new(300));
+        assert_eq!(bucket.liquidity.total_borrowed, Uint128::new(200));
+        assert_eq!(bucket.total_owed, lender.amount_at_maturity);
+        assert!(LEGACY_ESCROW.may_load(deps.as_ref().storage).unwrap().is_none());
+        assert!(LEGACY_LENDERS.may_load(deps.as_ref().storage).unwrap().is_none());
+        assert!(LEGACY_BORROWERS.may_load(deps.as_ref().storage).unwrap().is_none());
+        assert!(LEGACY_EARNINGS.may_load(deps.as_ref().storage).unwrap().is_none());
+
+        // Migrating again to the same version runs no steps
+        let res = migrate_contract(deps.as_mut(), mock_env(), MigrateMsg::default()).unwrap();
+        assert!(res.attributes.iter().any(|attr| attr.key == "steps" && attr.value.is_empty()));
+    }
+
+    #[test]
+    fn v0_1_0_needs_the_missing_config() {
+        let mut deps = mock_dependencies();
+        v0_1_0_fixture(deps.as_mut().storage);
+
+        let err = migrate_contract(deps.as_mut(), mock_env(), MigrateMsg::default()).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidMigration { .. }));
+
+        // Without a stored config the owner has to be named
+        let msg = MigrateMsg { owner: None, ..migrate_msg() };
+        let err = migrate_contract(deps.as_mut(), mock_env(), msg).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidMigration { reason } if reason.contains("owner")));
+    }
+
+    #[test]
+    fn stored_legacy_config_wins() {
+        let mut deps = mock_dependencies();
+        v0_1_0_fixture(deps.as_mut().storage);
+        let legacy_config = LegacyConfig { owner: Addr::unchecked("stored_owner"), token: Addr::unchecked("stored_token") };
+        LEGACY_CONFIG.save(deps.as_mut().storage, &legacy_config).unwrap();
+
+        let msg = MigrateMsg { owner: None, token: None, ..migrate_msg() };
+        migrate_contract(deps.as_mut(), mock_env(), msg).unwrap();
+        let config = CONFIG.load(deps.as_ref().storage).unwrap();
+        assert_eq!(config.owner, Addr::unchecked("stored_owner"));
+        assert_eq!(config.token, Addr::unchecked("stored_token"));
+    }
+
+    #[test]
+    fn refuses_downgrade_and_other_contracts() {
+        let mut deps = mock_dependencies();
+        set_contract_version(deps.as_mut().storage, CONTRACT_NAME, "9.0.0").unwrap();
+        let err = migrate_contract(deps.as_mut(), mock_env(), migrate_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidMigration { reason } if reason.contains("downgrade")));
+
+        set_contract_version(deps.as_mut().storage, "crates.io:cw20-base", "0.1.0").unwrap();
+        let err = migrate_contract(deps.as_mut(), mock_env(), migrate_msg()).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidMigration { reason } if reason.contains("expected contract")));
+    }
+}
@@ -0,0 +1,41 @@
+// A bare oracle answering `OracleQueryMsg`, anyone can set its prices
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{to_binary, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Response, StdResult};
+use cw_storage_plus::Map;
+
+use crate::oracle::{OracleQueryMsg, PriceResponse};
+
+pub const PRICES: Map<&str, PriceResponse> = Map::new("mock_prices");
+
+#[cw_serde]
+pub struct InstantiateMsg {}
+
+#[cw_serde]
+pub enum ExecuteMsg {
+    // `ema_price` defaults to `price` and `updated_at` to the current block time
+    SetPrice { asset: String, price: Decimal, ema_price: Option<Decimal>, updated_at: Option<u64> },
+}
+
+pub fn instantiate(_deps: DepsMut, _env: Env, _info: MessageInfo, _msg: InstantiateMsg) -> StdResult<Response> {
+    Ok(Response::new())
+}
+
+pub fn execute(deps: DepsMut, env: Env, _info: MessageInfo, msg: ExecuteMsg) -> StdResult<Response> {
+    match msg {
+        ExecuteMsg::SetPrice { asset, price, ema_price, updated_at } => {
+            let response = PriceResponse {
+                price,
+                ema_price: ema_price.unwrap_or(price),
+                updated_at: updated_at.unwrap_or_else(|| env.block.time.seconds()),
+            };
+            PRICES.save(deps.storage, &asset, &response)?;
+            Ok(Response::new().add_attribute("action", "set_price").add_attribute("asset", asset))
+        }
+    }
+}
+
+pub fn query(deps: Deps, _env: Env, msg: OracleQueryMsg) -> StdResult<Binary> {
+    match msg {
+        OracleQueryMsg::Price { asset } => to_binary(&PRICES.load(deps.storage, &asset)?),
+    }
+}
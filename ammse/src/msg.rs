@@ -1,35 +1,116 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Coin, Uint128, Decimal, Addr};
+use cosmwasm_std::{Coin, Uint128, Decimal, Decimal256, Addr, Binary};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use cw20::Cw20ReceiveMsg;
 
+use crate::quote::SignedQuote;
+use crate::state::{CollateralAsset, Config, LoanStatus, OracleConfig, OwnershipProposal, PausableAction, PauseState, Role, TermRate};
+
 #[cw_serde]
 pub struct InstantiateMsg {
-    // pub admin: Option<String>,
-    // pub base_interest_rate: Decimal,
-    // pub fee_percentage: Decimal,
+    // defaults to the instantiating address
+    pub admin: Option<String>,
+    pub token: String,
+    pub base_interest_rate: Decimal,
+    pub slope1: Decimal,
+    pub slope2: Decimal,
+    pub optimal_utilization: Decimal,
+    pub fee_percentage: Decimal,
+    pub min_duration: u64,
+    pub max_duration: u64,
+    pub max_borrow_amount: Uint128,
+    pub close_factor: Decimal,
+    pub close_factor_dust: Uint128,
+    pub liquidation_bonus: Decimal,
+    pub backend_pubkey: Option<Binary>,
+    pub term_rates: Vec<TermRate>,
 }
 
 #[cw_serde]
 pub enum ExecuteMsg {
-    Increment {},
-    Reset { count: i32 },
     //AddToEscrow { amount : Coin }, 
     //AddCollateral { amount : Coin},
-    ReceiveForCollateral(Cw20ReceiveMsg),
-    RedeemForCollateral{},
-    BorrowFromPool ( Cw20ReceiveMsg ),
-    LendToPool(Cw20ReceiveMsg),
-    EarnToPool(Cw20ReceiveMsg),
-    LendToPoolV2{lender:Addr, amount: Uint128, duration:u64 },
+    // Cw20 `Send` lands here, the hook message selects lend, earn, escrow, repay or collateral
+    Receive(Cw20ReceiveMsg),
+    RedeemForCollateral{ escrow_id: u64 },
+    // With a quote the loan is fixed at the backend's rate
+    BorrowFromPool { amount: Uint128, maturity_date: u64, quote: Option<SignedQuote> },
+    // Operator only, opens a lender position for a trade matched off-chain.
+    // A backend quote made for the lender sets the rate, the configured term rate applies without one
+    SettleBackendTrade { lender: String, amount: Uint128, maturity_date: u64, backing: TradeBacking, quote: Option<SignedQuote> },
+    WithdrawMatured { position_id: u64 },
+    WithdrawEarn { amount: Option<Uint128> },
+    // Refused if the remaining collateral no longer covers the sender's open loans
+    WithdrawCollateral { asset: String, amount: Uint128 },
+    // Anyone can liquidate an undercollateralized or overdue loan, the repaid tokens
+    // are pulled from the sender's allowance and paid back in the borrower's collateral
+    Liquidate { borrower: String, loan_id: u64, repay_amount: Uint128 },
+    // Anyone can settle a maturity once it has expired
+    SettleMaturity { maturity_date: u64 },
+    // Owner only
+    CreateMaturity { maturity_date: u64 },
+    SetCollateralAsset { asset: String, price: Decimal, max_ltv: Decimal, liquidation_threshold: Decimal },
+    // Collateral is priced by `oracle` from then on, see `crate::oracle` for the query it has to answer
+    SetOracle { oracle: String, max_price_age: u64, max_price_deviation: Decimal },
+    // Sets or rotates the key backend quotes are checked against, `None` stops accepting quotes
+    SetBackendPubkey { pubkey: Option<Binary> },
+    UpdateConfig(Box<ConfigUpdate>),
+    ProposeNewOwner { new_owner: String, expires_in: u64 },
+    CancelOwnershipProposal {},
+    GrantRole { role: Role, address: String },
+    RevokeRole { role: Role, address: String },
+    // Guardian pauses and the owner unpauses, `None` targets the global flag
+    Pause { action: Option<PausableAction> },
+    Unpause { action: Option<PausableAction> },
+    // Fee collector only, `None` withdraws every collected fee
+    WithdrawFees { amount: Option<Uint128> },
+    // Called by the proposed owner before the proposal expires
+    AcceptOwnership {},
+}
+
+// Settings the migration steps need, only read by the steps that run
+#[cw_serde]
+#[derive(Default)]
+pub struct MigrateMsg {
+    // owner and pool token, 0.1.0 never saved its config so they have to be given again
+    pub owner: Option<String>,
+    pub token: Option<String>,
+    // fills in the config fields 0.1.0 did not store
+    pub config: ConfigUpdate,
+}
+
+// Where the tokens behind a settled backend trade come from
+#[cw_serde]
+pub enum TradeBacking {
+    // tokens the lender already holds in one of their escrows
+    Escrow { escrow_id: u64 },
+    // pulled with `TransferFrom` against the lender's allowance to this contract
+    Allowance {},
+}
+
+// Fields left as `None` keep their current value
+#[cw_serde]
+#[derive(Default)]
+pub struct ConfigUpdate {
+    pub base_interest_rate: Option<Decimal>,
+    pub slope1: Option<Decimal>,
+    pub slope2: Option<Decimal>,
+    pub optimal_utilization: Option<Decimal>,
+    pub fee_percentage: Option<Decimal>,
+    pub min_duration: Option<u64>,
+    pub max_duration: Option<u64>,
+    pub max_borrow_amount: Option<Uint128>,
+    pub close_factor: Option<Decimal>,
+    pub close_factor_dust: Option<Uint128>,
+    pub liquidation_bonus: Option<Decimal>,
+    pub term_rates: Option<Vec<TermRate>>,
 }
 
 #[cw_serde]
 #[derive(QueryResponses)]
 pub enum QueryMsg {
-    // GetCount returns the current count as a json-encoded number
-    #[returns(EscrowResponse)]
+    #[returns(EscrowsResponse)]
     Escrow { address: String },
     #[returns(LenderPoolResponse)]
     LendToPool { address: String },
@@ -37,32 +118,188 @@ pub enum QueryMsg {
     BorrowFromPool { address: String },
     #[returns(Pool)]
     Pool {},
+    #[returns(Config)]
+    Config {},
+    #[returns(Option<OwnershipProposal>)]
+    OwnershipProposal {},
+    // Maturities that have not been settled yet
+    #[returns(MaturitiesResponse)]
+    Maturities {},
+    #[returns(CollateralResponse)]
+    Collateral { address: String, asset: String },
+    // Every asset the address holds as collateral
+    #[returns(CollateralsResponse)]
+    Collaterals { address: String },
+    #[returns(CollateralAssetsResponse)]
+    CollateralAssets {},
+    // Cw20 contracts the receive hook takes deposits from
+    #[returns(AcceptedTokensResponse)]
+    AcceptedTokens {},
+    #[returns(Option<OracleConfig>)]
+    Oracle {},
+    #[returns(HealthFactorResponse)]
+    HealthFactor { address: String },
+    #[returns(RatesResponse)]
+    Rates {},
+    #[returns(PauseState)]
+    PauseState {},
+    #[returns(RoleHoldersResponse)]
+    RoleHolders { role: Role },
+    #[returns(Uint128)]
+    CollectedFees {},
+    #[returns(EarnPoolResponse)]
+    EarnPool {},
+    #[returns(EarnBalanceResponse)]
+    EarnBalance { address: String },
+    #[returns(EarnConversionResponse)]
+    ConvertToShares { assets: Uint128 },
+    #[returns(EarnConversionResponse)]
+    ConvertToAssets { shares: Uint128 },
 }
 
 #[cw_serde]
 pub enum Cw20HookMsg {
+    // With a quote the position locks the backend's rate instead of the term rate
+    Lend { maturity_date: u64, quote: Option<SignedQuote> },
+    Earn {},
     Escrow { time: u64 },
+    Repay { loan_id: u64 },
+    Collateral {},
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct EscrowsResponse {
+    pub escrows: Vec<EscrowResponse>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct EscrowResponse {
+    pub escrow_id: u64,
     pub amount: Uint128,
     pub time: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct LenderPoolResponse {
+    pub positions: Vec<LenderPositionResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct LenderPositionResponse {
+    pub position_id: u64,
     pub amount_lent: Uint128,
     pub maturity_date: u64,
+    pub rate: Decimal,
+    pub amount_at_maturity: Uint128,
+    // supply index the position entered at
+    pub supply_index: Decimal256,
+    // the principal grown at the variable supply rate since then, next to the fixed payout
+    pub supply_value: Uint128,
+    pub amount_paid: Uint128,
+    pub settled: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct BorrowerPoolResponse {
-    pub amount_borrowed: Uint128,
+    pub loans: Vec<LoanResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct LoanResponse {
+    pub loan_id: u64,
+    pub principal: Uint128,
+    pub interest_accrued: Uint128,
+    pub amount_repaid: Uint128,
+    pub start_time: u64,
     pub maturity_date: u64,
+    pub status: LoanStatus,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct Pool {
     pub liquidity: Coin,  // total tokens in the pool
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct EarnPoolResponse {
+    pub total_assets: Uint128,
+    pub total_shares: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct EarnBalanceResponse {
+    pub shares: Uint128,
+    pub assets: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct EarnConversionResponse {
+    pub assets: Uint128,
+    pub shares: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RoleHoldersResponse {
+    pub role: Role,
+    pub holders: Vec<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct RatesResponse {
+    pub utilization: Decimal,
+    // annualized
+    pub borrow_rate: Decimal,
+    pub supply_rate: Decimal,
+    // cumulative indices accrued up to the queried block
+    pub borrow_index: Decimal256,
+    pub supply_index: Decimal256,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct MaturitiesResponse {
+    pub maturities: Vec<MaturityResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct MaturityResponse {
+    pub maturity_date: u64,
+    pub total_tokens: Uint128,
+    pub total_borrowed: Uint128,
+    // still owed to the maturity's lenders
+    pub total_owed: Uint128,
+    pub utilization: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct CollateralResponse {
+    pub asset: Addr,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct CollateralsResponse {
+    pub collaterals: Vec<CollateralResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct CollateralAssetsResponse {
+    pub assets: Vec<(Addr, CollateralAsset)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct AcceptedTokensResponse {
+    // lends, earn deposits, escrows and repayments
+    pub pool_token: Addr,
+    // collateral deposits
+    pub collateral_assets: Vec<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct HealthFactorResponse {
+    pub collateral_value: Uint128,
+    pub debt_value: Uint128,
+    // what can still be borrowed before reaching the max LTV
+    pub remaining_borrow_capacity: Uint128,
+    // `None` while the address has no debt
+    pub health_factor: Option<Decimal>,
+}
@@ -0,0 +1,121 @@
+use cosmwasm_schema::{cw_serde, QueryResponses};
+use cosmwasm_std::{Addr, Decimal, Deps};
+
+use crate::error::ContractError;
+use crate::state::{CollateralAsset, OracleConfig, COLLATERAL_ASSETS, ORACLE};
+
+// Query the configured oracle has to answer
+#[cw_serde]
+#[derive(QueryResponses)]
+pub enum OracleQueryMsg {
+    // `asset` is the cw20 address of the collateral
+    #[returns(PriceResponse)]
+    Price { asset: String },
+}
+
+#[cw_serde]
+pub struct PriceResponse {
+    // value of one unit of the asset in pool tokens
+    pub price: Decimal,
+    // the oracle's moving average, the spot price has to stay close to it
+    pub ema_price: Decimal,
+    // block time of the last update in seconds
+    pub updated_at: u64,
+}
+
+// Asks the oracle for the price of `asset` and refuses zero, stale, future or deviating prices
+pub fn oracle_price(deps: Deps, oracle: &OracleConfig, asset: &Addr, now: u64) -> Result<Decimal, ContractError> {
+    let response: PriceResponse = deps
+        .querier
+        .query_wasm_smart(&oracle.address, &OracleQueryMsg::Price { asset: asset.to_string() })?;
+    if response.price.is_zero() || response.ema_price.is_zero() {
+        return Err(ContractError::InvalidPrice { asset: asset.to_string() });
+    }
+    // A timestamp ahead of the chain would keep the price fresh forever
+    if response.updated_at > now {
+        return Err(ContractError::FuturePrice { asset: asset.to_string(), updated_at: response.updated_at });
+    }
+    if now - response.updated_at > oracle.max_price_age {
+        return Err(ContractError::StalePrice { asset: asset.to_string(), updated_at: response.updated_at });
+    }
+    let deviation = response.price.abs_diff(response.ema_price) / response.ema_price;
+    if deviation > oracle.max_price_deviation {
+        return Err(ContractError::PriceDeviation {
+            asset: asset.to_string(),
+            price: response.price,
+            ema_price: response.ema_price,
+        });
+    }
+    Ok(response.price)
+}
+
+// Risk parameters of a registered collateral asset, priced by the oracle once one is set
+pub fn load_collateral_asset(deps: Deps, asset: &Addr, now: u64) -> Result<Option<CollateralAsset>, ContractError> {
+    let params = match COLLATERAL_ASSETS.may_load(deps.storage, asset)? {
+        Some(params) => params,
+        None => return Ok(None),
+    };
+    match ORACLE.may_load(deps.storage)? {
+        Some(oracle) => Ok(Some(CollateralAsset { price: oracle_price(deps, &oracle, asset, now)?, ..params })),
+        None => Ok(Some(params)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{mock_dependencies, MockApi, MockQuerier, MockStorage};
+    use cosmwasm_std::{from_binary, to_binary, ContractResult, OwnedDeps, SystemResult, WasmQuery};
+
+    // Serves a single price from the mocked oracle contract
+    fn mock_price(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, price: PriceResponse) {
+        deps.querier.update_wasm(move |query| match query {
+            WasmQuery::Smart { contract_addr, msg } if contract_addr == "oracle" => {
+                let OracleQueryMsg::Price { asset } = from_binary(msg).unwrap();
+                assert_eq!(asset, "atom");
+                SystemResult::Ok(ContractResult::Ok(to_binary(&price).unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+    }
+
+    #[test]
+    fn oracle_prices_are_checked_for_age_and_deviation() {
+        let mut deps = mock_dependencies();
+        let atom = Addr::unchecked("atom");
+        let params = CollateralAsset {
+            price: Decimal::one(),
+            max_ltv: Decimal::percent(50),
+            liquidation_threshold: Decimal::percent(80),
+        };
+        COLLATERAL_ASSETS.save(deps.as_mut().storage, &atom, &params).unwrap();
+
+        // The registered price applies until an oracle is set
+        let loaded = load_collateral_asset(deps.as_ref(), &atom, 1_000).unwrap().unwrap();
+        assert_eq!(loaded.price, Decimal::one());
+        assert_eq!(load_collateral_asset(deps.as_ref(), &Addr::unchecked("osmo"), 1_000).unwrap(), None);
+
+        let oracle = OracleConfig {
+            address: Addr::unchecked("oracle"),
+            max_price_age: 60,
+            max_price_deviation: Decimal::percent(10),
+        };
+        ORACLE.save(deps.as_mut().storage, &oracle).unwrap();
+        mock_price(&mut deps, PriceResponse { price: Decimal::percent(210), ema_price: Decimal::percent(200), updated_at: 940 });
+        let loaded = load_collateral_asset(deps.as_ref(), &atom, 1_000).unwrap().unwrap();
+        assert_eq!(loaded, CollateralAsset { price: Decimal::percent(210), ..params });
+
+        let err = oracle_price(deps.as_ref(), &oracle, &atom, 1_001).unwrap_err();
+        assert!(matches!(err, ContractError::StalePrice { asset, updated_at: 940 } if asset == "atom"));
+        let err = oracle_price(deps.as_ref(), &oracle, &atom, 939).unwrap_err();
+        assert!(matches!(err, ContractError::FuturePrice { asset, updated_at: 940 } if asset == "atom"));
+
+        mock_price(&mut deps, PriceResponse { price: Decimal::percent(230), ema_price: Decimal::percent(200), updated_at: 1_000 });
+        let err = oracle_price(deps.as_ref(), &oracle, &atom, 1_000).unwrap_err();
+        assert!(matches!(err, ContractError::PriceDeviation { price, .. } if price == Decimal::percent(230)));
+
+        mock_price(&mut deps, PriceResponse { price: Decimal::zero(), ema_price: Decimal::percent(200), updated_at: 1_000 });
+        let err = oracle_price(deps.as_ref(), &oracle, &atom, 1_000).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidPrice { asset } if asset == "atom"));
+    }
+}
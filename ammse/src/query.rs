@@ -1,65 +1,207 @@
-use cosmwasm_std::{Addr, Deps, StdError, StdResult};
+use cosmwasm_std::{Addr, Deps, Env, Order, StdError, StdResult, Uint128};
 
-use crate::msg::{ EscrowResponse, LenderPoolResponse, BorrowerPoolResponse, Pool};
-use crate::state::{CONFIG, ESCROW, LENDERS, BORROWERS, POOL};
+use crate::msg::{ EscrowResponse, EscrowsResponse, LenderPoolResponse, LenderPositionResponse, BorrowerPoolResponse, LoanResponse, Pool, EarnPoolResponse, EarnBalanceResponse, EarnConversionResponse, MaturitiesResponse, MaturityResponse, CollateralResponse, CollateralsResponse, CollateralAssetsResponse, AcceptedTokensResponse, HealthFactorResponse, RatesResponse, RoleHoldersResponse};
+use crate::health::account_health;
+use crate::interest::{claim_at_index, utilization};
+use crate::state::{ESCROW, LENDERS, COLLATERALS, COLLATERAL_ASSETS, ORACLE, VAULT, INTEREST_INDEX, MATURITIES, InterestIndex, BORROWERS, POOL, CONFIG, EARN_POOL, EARN_SHARES, OWNERSHIP_PROPOSAL, PAUSE_STATE, ROLES, COLLECTED_FEES, Config, EarnPool, OracleConfig, OwnershipProposal, PauseState, Role};
 
-pub fn query_escrow(deps: Deps, user: Addr) -> StdResult<EscrowResponse> {
-    let escrow = ESCROW.may_load(deps.storage)?;
+pub fn query_escrow(deps: Deps, user: Addr) -> StdResult<EscrowsResponse> {
+    let escrows = ESCROW
+        .prefix(&user)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (escrow_id, escrow) = item?;
+            Ok(EscrowResponse {
+                escrow_id,
+                amount: escrow.amount,
+                time: escrow.time,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
 
-    if escrow.is_none()  {
-        return Err(StdError::generic_err("No escrow found"));
+    if escrows.is_empty() {
+        return Err(StdError::generic_err("Escrow not found for user"));
     }
-    let escrows = escrow.unwrap();
 
-    if escrows.user != user {
-        return Err(StdError::generic_err("Escrow not found for user"));
-    }   
-        
-    Ok(EscrowResponse {
-        amount: escrows.amount,
-        time: escrows.time,
-    })
+    Ok(EscrowsResponse { escrows })
 }
 
-pub fn query_lend_to_pool(deps: Deps, user: Addr) -> StdResult<LenderPoolResponse> {
-    let config = CONFIG.load(deps.storage)?;
-    let lenderers = LENDERS.may_load(deps.storage)?;
+pub fn query_lend_to_pool(deps: Deps, env: Env, user: Addr) -> StdResult<LenderPoolResponse> {
+    let index = current_index(deps, &env)?;
+    let positions = LENDERS
+        .prefix(&user)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (position_id, lender) = item?;
+            Ok(LenderPositionResponse {
+                position_id,
+                amount_lent: lender.amount_lent,
+                maturity_date: lender.maturity_date,
+                rate: lender.rate,
+                amount_at_maturity: lender.amount_at_maturity,
+                supply_index: lender.supply_index,
+                supply_value: claim_at_index(lender.amount_lent, lender.supply_index, index.supply_index)?,
+                amount_paid: lender.amount_paid,
+                settled: lender.settled,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
 
-    if lenderers.is_none() {
-        return Err(StdError::generic_err("No Lenders found"));
+    if positions.is_empty() {
+        return Err(StdError::generic_err("Lend Tokens not found for user"));
     }
-    let lender = lenderers.unwrap();
 
-    if lender.lender != user {
-        return Err(StdError::generic_err("Lend Tokens not found for user"));
+    Ok(LenderPoolResponse { positions })
+}
+
+pub fn query_borrow_to_pool(deps: Deps, user: Addr) -> StdResult<BorrowerPoolResponse> {
+    let loans = BORROWERS
+        .prefix(&user)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (loan_id, loan) = item?;
+            Ok(LoanResponse {
+                loan_id,
+                principal: loan.principal,
+                interest_accrued: loan.interest_accrued,
+                amount_repaid: loan.amount_repaid,
+                start_time: loan.start_time,
+                maturity_date: loan.maturity_date,
+                status: loan.status,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    if loans.is_empty() {
+        return Err(StdError::generic_err("Loans not found for user"));
     }
 
-   
-   Ok(LenderPoolResponse {
-        amount_lent: lender.amount_lent,
-        maturity_date: lender.maturity_date,
+    Ok(BorrowerPoolResponse { loans })
+}
+
+pub fn query_config(deps: Deps) -> StdResult<Config> {
+    CONFIG.load(deps.storage)
+}
+
+pub fn query_ownership_proposal(deps: Deps) -> StdResult<Option<OwnershipProposal>> {
+    OWNERSHIP_PROPOSAL.may_load(deps.storage)
+}
+
+pub fn query_maturities(deps: Deps) -> StdResult<MaturitiesResponse> {
+    let maturities = MATURITIES
+        .range(deps.storage, None, None, Order::Ascending)
+        .filter(|item| !matches!(item, Ok((_, bucket)) if bucket.settled))
+        .map(|item| {
+            let (maturity_date, bucket) = item?;
+            Ok(MaturityResponse {
+                maturity_date,
+                total_tokens: bucket.liquidity.total_tokens,
+                total_borrowed: bucket.liquidity.total_borrowed,
+                total_owed: bucket.outstanding(),
+                utilization: utilization(&bucket.liquidity),
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(MaturitiesResponse { maturities })
+}
+
+pub fn query_collateral(deps: Deps, address: Addr, asset: Addr) -> StdResult<CollateralResponse> {
+    let amount = COLLATERALS.may_load(deps.storage, (&address, &asset))?.unwrap_or_default();
+    Ok(CollateralResponse { asset, amount })
+}
+
+pub fn query_collaterals(deps: Deps, address: Addr) -> StdResult<CollateralsResponse> {
+    let collaterals = COLLATERALS
+        .prefix(&address)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (asset, amount) = item?;
+            Ok(CollateralResponse { asset, amount })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(CollateralsResponse { collaterals })
+}
+
+pub fn query_collateral_assets(deps: Deps) -> StdResult<CollateralAssetsResponse> {
+    let assets = COLLATERAL_ASSETS
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(CollateralAssetsResponse { assets })
+}
+
+pub fn query_accepted_tokens(deps: Deps) -> StdResult<AcceptedTokensResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let collateral_assets = COLLATERAL_ASSETS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(AcceptedTokensResponse { pool_token: config.token, collateral_assets })
+}
+
+// Current rates of the utilization curve
+pub fn query_rates(deps: Deps, env: Env) -> StdResult<RatesResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let vault = VAULT.may_load(deps.storage)?.unwrap_or_default();
+    let utilization = utilization(&vault);
+    let model = config.rate_model();
+    let index = current_index(deps, &env)?;
+
+    Ok(RatesResponse {
+        utilization,
+        borrow_rate: model.borrow_rate(utilization),
+        supply_rate: model.supply_rate(utilization, config.fee_percentage),
+        borrow_index: index.borrow_index,
+        supply_index: index.supply_index,
     })
 }
 
-pub fn query_borrow_to_pool(deps: Deps, user: Addr) -> StdResult<BorrowerPoolResponse> {
+// Interest indices as they would be accrued at the current block, without saving them
+fn current_index(deps: Deps, env: &Env) -> StdResult<InterestIndex> {
     let config = CONFIG.load(deps.storage)?;
-    let borrowers = BORROWERS.may_load(deps.storage)?;
+    let vault = VAULT.may_load(deps.storage)?.unwrap_or_default();
+    let now = env.block.time.seconds();
+    let mut index = INTEREST_INDEX.may_load(deps.storage)?.unwrap_or_else(|| InterestIndex::new(now));
+    index.accrue(&config, utilization(&vault), now)?;
+    Ok(index)
+}
 
-    if borrowers.is_none() {
-        return Err(StdError::generic_err("No Lenders found"));
-    }
-    let borrower = borrowers.unwrap();
+pub fn query_oracle(deps: Deps) -> StdResult<Option<OracleConfig>> {
+    ORACLE.may_load(deps.storage)
+}
 
-    if borrower.borrower != user {
-        return Err(StdError::generic_err("Lend Tokens not found for user"));
-    }
-    
-   Ok(BorrowerPoolResponse {
-        amount_borrowed: borrower.amount_borrowed,
-        maturity_date: borrower.maturity_date,
+pub fn query_health_factor(deps: Deps, env: Env, address: Addr) -> StdResult<HealthFactorResponse> {
+    let index = current_index(deps, &env)?;
+    let health = account_health(deps, env.block.time.seconds(), &address, index.borrow_index)
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+
+    Ok(HealthFactorResponse {
+        collateral_value: health.collateral_value,
+        debt_value: health.debt,
+        remaining_borrow_capacity: health.remaining_capacity(),
+        health_factor: health.health_factor(),
     })
 }
 
+pub fn query_pause_state(deps: Deps) -> StdResult<PauseState> {
+    Ok(PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default())
+}
+
+pub fn query_role_holders(deps: Deps, role: Role) -> StdResult<RoleHoldersResponse> {
+    let holders = ROLES
+        .prefix(role.as_str())
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+
+    Ok(RoleHoldersResponse { role, holders })
+}
+
+pub fn query_collected_fees(deps: Deps) -> StdResult<Uint128> {
+    Ok(COLLECTED_FEES.may_load(deps.storage)?.unwrap_or_default())
+}
+
 pub fn query_pool(deps: Deps) -> StdResult<Pool> {
   
     let pools = POOL.load(deps.storage)?;
@@ -68,4 +210,45 @@ pub fn query_pool(deps: Deps) -> StdResult<Pool> {
     Ok(Pool {
         liquidity: pool,
     })
-}
\ No newline at end of file
+}
+
+fn load_earn_pool(deps: Deps) -> StdResult<EarnPool> {
+    Ok(EARN_POOL.may_load(deps.storage)?.unwrap_or_default())
+}
+
+pub fn query_earn_pool(deps: Deps) -> StdResult<EarnPoolResponse> {
+    let pool = load_earn_pool(deps)?;
+
+    Ok(EarnPoolResponse {
+        total_assets: pool.total_assets,
+        total_shares: pool.total_shares,
+    })
+}
+
+pub fn query_earn_balance(deps: Deps, user: Addr) -> StdResult<EarnBalanceResponse> {
+    let pool = load_earn_pool(deps)?;
+    let shares = EARN_SHARES.may_load(deps.storage, &user)?.unwrap_or_default();
+
+    Ok(EarnBalanceResponse {
+        shares,
+        assets: pool.convert_to_assets(shares),
+    })
+}
+
+pub fn query_convert_to_shares(deps: Deps, assets: Uint128) -> StdResult<EarnConversionResponse> {
+    let pool = load_earn_pool(deps)?;
+
+    Ok(EarnConversionResponse {
+        assets,
+        shares: pool.convert_to_shares(assets),
+    })
+}
+
+pub fn query_convert_to_assets(deps: Deps, shares: Uint128) -> StdResult<EarnConversionResponse> {
+    let pool = load_earn_pool(deps)?;
+
+    Ok(EarnConversionResponse {
+        assets: pool.convert_to_assets(shares),
+        shares,
+    })
+}
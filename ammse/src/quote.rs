@@ -0,0 +1,95 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{from_binary, Addr, Binary, Decimal, DepsMut, Empty, Env, Uint128};
+use sha2::{Digest, Sha256};
+
+use crate::error::ContractError;
+use crate::state::{CONFIG, USED_QUOTE_NONCES};
+
+// A quote from the off-chain backend, lends and borrows carrying one execute at its rate
+#[cw_serde]
+pub struct SignedQuote {
+    // JSON encoded `QuotePayload`, signed byte for byte
+    pub payload: Binary,
+    // 64 byte secp256k1 signature over the sha256 of `payload`
+    pub signature: Binary,
+}
+
+#[cw_serde]
+pub enum QuoteAction {
+    Lend,
+    Borrow,
+}
+
+#[cw_serde]
+pub struct QuotePayload {
+    pub action: QuoteAction,
+    // the lender or borrower the quote was made for
+    pub account: String,
+    pub amount: Uint128,
+    pub maturity_date: u64,
+    pub rate: Decimal,
+    // every nonce is accepted once
+    pub nonce: u64,
+    // block time in seconds after which the quote is refused
+    pub expires_at: u64,
+    // the deployment the quote is for, it can not be replayed against another contract or chain
+    pub contract: String,
+    pub chain_id: String,
+}
+
+impl QuotePayload {
+    // A quote only covers the exact trade it was made for
+    pub fn assert_matches(&self, action: QuoteAction, account: &Addr, amount: Uint128, maturity_date: u64) -> Result<(), ContractError> {
+        if self.action != action || self.account != account.as_str() || self.amount != amount || self.maturity_date != maturity_date {
+            return Err(ContractError::QuoteMismatch {});
+        }
+        Ok(())
+    }
+}
+
+// Checks the backend signed the quote for this contract and chain and that it is neither expired
+// nor used, then uses up its nonce
+pub fn verify_quote(deps: DepsMut, env: &Env, quote: &SignedQuote) -> Result<QuotePayload, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let pubkey = config.backend_pubkey.ok_or(ContractError::NoBackendKey {})?;
+    let hash = Sha256::digest(quote.payload.as_slice());
+    if !matches!(deps.api.secp256k1_verify(&hash, &quote.signature, &pubkey), Ok(true)) {
+        return Err(ContractError::InvalidQuoteSignature {});
+    }
+
+    let payload: QuotePayload = from_binary(&quote.payload)?;
+    if payload.contract != env.contract.address.as_str() || payload.chain_id != env.block.chain_id {
+        return Err(ContractError::QuoteWrongDeployment {});
+    }
+    if env.block.time.seconds() > payload.expires_at {
+        return Err(ContractError::QuoteExpired { expires_at: payload.expires_at });
+    }
+    if USED_QUOTE_NONCES.has(deps.storage, payload.nonce) {
+        return Err(ContractError::QuoteNonceUsed { nonce: payload.nonce });
+    }
+    USED_QUOTE_NONCES.save(deps.storage, payload.nonce, &Empty {})?;
+    Ok(payload)
+}
+
+// Signs quotes with a fixed key the way the backend does
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use cosmwasm_std::to_binary;
+    use k256::ecdsa::{signature::Signer, Signature, SigningKey};
+
+    fn backend_key() -> SigningKey {
+        SigningKey::from_bytes(&[7u8; 32].into()).unwrap()
+    }
+
+    // Compressed public key of the test backend
+    pub fn backend_pubkey() -> Binary {
+        Binary::from(backend_key().verifying_key().to_encoded_point(true).as_bytes())
+    }
+
+    pub fn sign_quote(payload: &QuotePayload) -> SignedQuote {
+        let payload = to_binary(payload).unwrap();
+        let signature: Signature = backend_key().sign(payload.as_slice());
+        SignedQuote { payload, signature: Binary::from(signature.to_bytes().as_slice()) }
+    }
+}
@@ -1,51 +1,370 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use cosmwasm_std::{Addr, Coin, Uint128};
-use cw_storage_plus:: Item;
+use cosmwasm_std::{Addr, Binary, Coin, Decimal, Decimal256, Empty, StdResult, Storage, Uint128, Uint256};
+use cw_storage_plus::{Item, Map};
 
-pub static ESCROW: Item<Escrow> = Item::new("escrows:");
+use crate::interest::{debt_at_index, grow_index, InterestRateModel};
+
+// Escrows keyed by (owner, escrow_id), so a user can hold many concurrent escrows
+pub static ESCROW: Map<(&Addr, u64), Escrow> = Map::new("escrow_positions");
+pub const ESCROW_COUNT: Item<u64> = Item::new("escrow_count");
 pub static POOL: Item<Pool> = Item::new("pool");
-pub static COLLATERALS: Item<Collateral> = Item::new("collaterals:");
+// Collateral balances keyed by (borrower, asset)
+pub static COLLATERALS: Map<(&Addr, &Addr), Uint128> = Map::new("collateral_balances");
+// Cw20 assets the owner accepts as collateral
+pub static COLLATERAL_ASSETS: Map<&Addr, CollateralAsset> = Map::new("collateral_assets");
+// Price feed for collateral, the registered prices apply while none is set
+pub const ORACLE: Item<OracleConfig> = Item::new("oracle");
 pub static VAULT: Item<Vault> = Item::new("vault");
-pub static LENDERS: Item<LenderInfo> = Item::new("lenders:");
-pub static BORROWERS: Item<BorrowerInfo> = Item::new("borrowers:");
+// Liquidity of each fixed maturity keyed by its maturity date, created by the owner
+pub const MATURITIES: Map<u64, MaturityBucket> = Map::new("maturities");
+// Cumulative borrow and supply indices, accrued lazily on every call that touches the vault
+pub const INTEREST_INDEX: Item<InterestIndex> = Item::new("interest_index");
+// Lender positions keyed by (lender, position_id), so a lender can hold many independent positions
+pub static LENDERS: Map<(&Addr, u64), LenderInfo> = Map::new("lender_positions");
+pub const LENDER_POSITION_COUNT: Item<u64> = Item::new("lender_position_count");
+// Borrower ledger keyed by (borrower, loan_id), loans stay in the ledger once repaid or defaulted
+pub static BORROWERS: Map<(&Addr, u64), BorrowerInfo> = Map::new("borrower_loans");
+pub const LOAN_COUNT: Item<u64> = Item::new("loan_count");
 pub const CONFIG: Item<Config> = Item::new("config");
-pub const EARNINGS: Item<Earnings> = Item::new("earnings");
-pub const STATE: Item<State> = Item::new("state");
+pub const OWNERSHIP_PROPOSAL: Item<OwnershipProposal> = Item::new("ownership_proposal");
+// Nonces of the backend quotes already executed
+pub static USED_QUOTE_NONCES: Map<u64, Empty> = Map::new("used_quote_nonces");
+// Role holders keyed by (role, address), managed by the owner
+pub static ROLES: Map<(&str, &Addr), Empty> = Map::new("roles");
+// Circuit breaker, set by a guardian and cleared by the owner
+pub const PAUSE_STATE: Item<PauseState> = Item::new("pause_state");
+// Protocol share of repaid interest, withdrawable by the fee collector
+pub const COLLECTED_FEES: Item<Uint128> = Item::new("collected_fees");
+// Earn pool totals and each user's share balance, shares are priced at total_assets / total_shares
+pub const EARN_POOL: Item<EarnPool> = Item::new("earn_pool");
+pub static EARN_SHARES: Map<&Addr, Uint128> = Map::new("earn_shares");
+pub const SECONDS_PER_YEAR: u64 = 31_536_000;
+
+// Returns the next id from an auto-incrementing counter
+fn next_id(store: &mut dyn Storage, counter: &Item<u64>) -> StdResult<u64> {
+    let id = counter.may_load(store)?.unwrap_or_default() + 1;
+    counter.save(store, &id)?;
+    Ok(id)
+}
+
+pub fn next_lender_position_id(store: &mut dyn Storage) -> StdResult<u64> {
+    next_id(store, &LENDER_POSITION_COUNT)
+}
+
+pub fn next_escrow_id(store: &mut dyn Storage) -> StdResult<u64> {
+    next_id(store, &ESCROW_COUNT)
+}
+
+pub fn next_loan_id(store: &mut dyn Storage) -> StdResult<u64> {
+    next_id(store, &LOAN_COUNT)
+}
+
 // Represents the collective vault where all tokens are pooled together
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq,  Eq, JsonSchema)]
 pub struct Vault {
+    // liquidity available to withdraw or borrow
     pub total_tokens: Uint128,
+    // principal currently lent out to borrowers, missing before 0.2.0
+    #[serde(default)]
+    pub total_borrowed: Uint128,
 }
 
 impl Default for Vault {
     fn default() -> Self {
         Vault {
             total_tokens: Uint128::zero(),
-           
+            total_borrowed: Uint128::zero(),
         }
     }
 }
 
-// Represents an individual lender's contribution and detailsasd
+// Represents an individual lender's contribution and details
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct LenderInfo {
    pub lender: Addr,
    pub amount_lent: Uint128,
+   pub start_time: u64,
    pub maturity_date: u64,
+   // annualized rate locked in when the position was opened
+   pub rate: Decimal,
+   // principal plus the interest at `rate` over the whole term
+   pub amount_at_maturity: Uint128,
+   // supply index when the position was opened, one for positions opened before it was recorded
+   #[serde(default = "Decimal256::one")]
+   pub supply_index: Decimal256,
+   // paid out so far, a short maturity pays its lenders as repayments come in
+   #[serde(default)]
+   pub amount_paid: Uint128,
+   // set once the position has been paid in full, it stays around as a record
+   #[serde(default)]
+   pub settled: bool,
+}
+
+// Lenders and borrowers of the same maturity share its liquidity, `VAULT` remains the total over all maturities
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct MaturityBucket {
+    pub maturity_date: u64,
+    pub liquidity: Vault,
+    // principal lent into the maturity
+    #[serde(default)]
+    pub total_principal: Uint128,
+    // what the bucket's lenders are owed at maturity
+    pub total_owed: Uint128,
+    // paid out to the bucket's lenders so far
+    #[serde(default)]
+    pub total_paid: Uint128,
+    // set once the maturity has expired and been settled
+    pub settled: bool,
+}
+
+impl MaturityBucket {
+    pub fn new(maturity_date: u64) -> Self {
+        MaturityBucket {
+            maturity_date,
+            liquidity: Vault::default(),
+            total_principal: Uint128::zero(),
+            total_owed: Uint128::zero(),
+            total_paid: Uint128::zero(),
+            settled: false,
+        }
+    }
+
+    // What the bucket's lenders are still owed
+    pub fn outstanding(&self) -> Uint128 {
+        self.total_owed.saturating_sub(self.total_paid)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct InterestIndex {
+    pub borrow_index: Decimal256,
+    pub supply_index: Decimal256,
+    pub last_updated: u64,
+}
+
+impl InterestIndex {
+    pub fn new(now: u64) -> Self {
+        InterestIndex {
+            borrow_index: Decimal256::one(),
+            supply_index: Decimal256::one(),
+            last_updated: now,
+        }
+    }
+
+    // Grows both indices at the rates implied by the vault's utilization since the last update
+    pub fn accrue(&mut self, config: &Config, utilization: Decimal, now: u64) -> StdResult<()> {
+        if now <= self.last_updated {
+            return Ok(());
+        }
+        let elapsed = now - self.last_updated;
+        let model = config.rate_model();
+        self.borrow_index = grow_index(self.borrow_index, model.borrow_rate(utilization), elapsed)?;
+        self.supply_index = grow_index(self.supply_index, model.supply_rate(utilization, config.fee_percentage), elapsed)?;
+        self.last_updated = now;
+        Ok(())
+    }
 }
 
+// Represents a single loan in a borrower's debt book, `principal` is the principal still outstanding
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct BorrowerInfo {
    pub borrower: Addr,
-   pub amount_borrowed: Uint128,
+   pub principal: Uint128,
+   pub interest_accrued: Uint128,
+   pub amount_repaid: Uint128,
+   pub start_time: u64,
    pub maturity_date: u64,
+   // borrow index the outstanding debt was last brought up to
+   pub borrow_index: Decimal256,
+   // rate quoted by the backend, the whole term's interest is owed from the start
+   // and the loan does not follow the borrow index
+   #[serde(default)]
+   pub fixed_rate: Option<Decimal>,
+   pub status: LoanStatus,
+}
+
+impl BorrowerInfo {
+    pub fn outstanding_debt(&self) -> Uint128 {
+        self.principal + self.interest_accrued
+    }
+
+    // Outstanding debt brought up to `borrow_index`
+    pub fn debt_at(&self, borrow_index: Decimal256) -> StdResult<Uint128> {
+        match self.fixed_rate {
+            Some(_) => Ok(self.outstanding_debt()),
+            None => debt_at_index(self.outstanding_debt(), self.borrow_index, borrow_index),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LoanStatus {
+    Open,
+    Repaid,
+    // liquidated after its maturity, whatever debt is left can still be repaid or liquidated
+    Defaulted,
+}
+
+impl LoanStatus {
+    // Whether the loan still counts against the borrower's collateral
+    pub fn is_outstanding(&self) -> bool {
+        matches!(self, LoanStatus::Open | LoanStatus::Defaulted)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
 pub struct Config {
     pub owner: Addr,
+    // the Cw20 token the pool accepts deposits in and pays out
     pub token: Addr,
+    // borrow rate at zero utilization
+    pub base_interest_rate: Decimal,
+    // shape of the utilization curve on top of the base rate
+    pub slope1: Decimal,
+    pub slope2: Decimal,
+    pub optimal_utilization: Decimal,
+    // reserve factor, the protocol's share of the interest borrowers pay
+    pub fee_percentage: Decimal,
+    // bounds on lend and borrow durations in seconds
+    pub min_duration: u64,
+    pub max_duration: u64,
+    pub max_borrow_amount: Uint128,
+    // share of a loan's debt a single liquidation can repay
+    pub close_factor: Decimal,
+    // debt at or below which a liquidation may repay the whole loan, zero before it was configurable
+    #[serde(default)]
+    pub close_factor_dust: Uint128,
+    // extra collateral value liquidators receive on top of what they repay
+    pub liquidation_bonus: Decimal,
+    // secp256k1 key the backend signs its quotes with, quoted lends and borrows are refused without one
+    pub backend_pubkey: Option<Binary>,
+    // fixed lending rates by minimum duration, ascending
+    pub term_rates: Vec<TermRate>,
+}
+
+// Deposits of at least `duration` seconds lock in `rate`, up to the next tier
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct TermRate {
+    pub duration: u64,
+    pub rate: Decimal,
+}
+
+impl Config {
+    // Rate of the longest tier the duration qualifies for
+    pub fn term_rate(&self, duration: u64) -> Option<Decimal> {
+        self.term_rates
+            .iter()
+            .rev()
+            .find(|tier| tier.duration <= duration)
+            .map(|tier| tier.rate)
+    }
+
+    pub fn rate_model(&self) -> InterestRateModel {
+        InterestRateModel {
+            base_rate: self.base_interest_rate,
+            slope1: self.slope1,
+            slope2: self.slope2,
+            optimal_utilization: self.optimal_utilization,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    // backend that settles off-chain trades
+    Operator,
+    // can pause the contract during an incident
+    Guardian,
+    // can withdraw collected protocol fees
+    FeeCollector,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Operator => "operator",
+            Role::Guardian => "guardian",
+            Role::FeeCollector => "fee_collector",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PausableAction {
+    Lend,
+    Borrow,
+    Earn,
+    Escrow,
+    Withdraw,
+    Liquidate,
+}
+
+impl PausableAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PausableAction::Lend => "lend",
+            PausableAction::Borrow => "borrow",
+            PausableAction::Earn => "earn",
+            PausableAction::Escrow => "escrow",
+            PausableAction::Withdraw => "withdraw",
+            PausableAction::Liquidate => "liquidate",
+        }
+    }
+}
+
+// `global` stops every action, the other flags stop a single one
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+pub struct PauseState {
+    pub global: bool,
+    pub lend: bool,
+    pub borrow: bool,
+    pub earn: bool,
+    pub escrow: bool,
+    pub withdraw: bool,
+    pub liquidate: bool,
+}
+
+impl PauseState {
+    fn flag_mut(&mut self, action: PausableAction) -> &mut bool {
+        match action {
+            PausableAction::Lend => &mut self.lend,
+            PausableAction::Borrow => &mut self.borrow,
+            PausableAction::Earn => &mut self.earn,
+            PausableAction::Escrow => &mut self.escrow,
+            PausableAction::Withdraw => &mut self.withdraw,
+            PausableAction::Liquidate => &mut self.liquidate,
+        }
+    }
+
+    // `None` targets the global flag
+    pub fn set(&mut self, action: Option<PausableAction>, paused: bool) {
+        match action {
+            Some(action) => *self.flag_mut(action) = paused,
+            None => self.global = paused,
+        }
+    }
+
+    pub fn is_paused(&self, action: PausableAction) -> bool {
+        self.global || match action {
+            PausableAction::Lend => self.lend,
+            PausableAction::Borrow => self.borrow,
+            PausableAction::Earn => self.earn,
+            PausableAction::Escrow => self.escrow,
+            PausableAction::Withdraw => self.withdraw,
+            PausableAction::Liquidate => self.liquidate,
+        }
+    }
+}
+
+// A pending ownership transfer, the proposed owner has to accept it before `expires_at`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct OwnershipProposal {
+    pub new_owner: Addr,
+    pub expires_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
@@ -53,10 +372,31 @@ pub struct Pool {
     pub liquidity: Coin,  // total tokens in the pool
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
-pub struct Collateral {
-    pub owner: Addr,
-    pub amount: Coin,
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct CollateralAsset {
+    // value of one unit of the asset in pool tokens, used while no oracle is set
+    pub price: Decimal,
+    // share of the collateral value that can be borrowed against
+    pub max_ltv: Decimal,
+    // share of the collateral value the debt may reach before the account can be liquidated
+    pub liquidation_threshold: Decimal,
+}
+
+// Oracle prices older than `max_price_age` seconds, or further than `max_price_deviation`
+// from the oracle's moving average, are refused
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
+pub struct OracleConfig {
+    pub address: Addr,
+    pub max_price_age: u64,
+    pub max_price_deviation: Decimal,
+}
+
+impl CollateralAsset {
+    // Value of `amount` of the asset in pool tokens, rounded down
+    pub fn value(&self, amount: Uint128) -> StdResult<Uint128> {
+        let value = Uint256::from(amount).checked_mul(self.price.atomics().into())?;
+        Ok(value.checked_div(Decimal::one().atomics().into())?.try_into()?)
+    }
 }
 
 // Execute messages
@@ -77,16 +417,38 @@ pub struct Escrow {
     pub time: u64,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
-pub struct Earnings {
-    pub user: Addr,            
-    pub amount_supplied: Uint128,  
-    pub last_updated: u64,    
-    
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq, JsonSchema)]
+// Earn deposits are held apart from the maturities, the interest settled maturities have
+// left over is added to `total_assets` and raises the price of every share
+pub struct EarnPool {
+    pub total_assets: Uint128,
+    pub total_shares: Uint128,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, JsonSchema)]
-pub struct State {
-    pub count: i32,
-    pub owner: Addr,
+impl EarnPool {
+    // Shares minted for a deposit of `assets`, rounded down in favour of the pool
+    pub fn convert_to_shares(&self, assets: Uint128) -> Uint128 {
+        if self.total_shares.is_zero() || self.total_assets.is_zero() {
+            return assets;
+        }
+        assets.multiply_ratio(self.total_shares, self.total_assets)
+    }
+
+    // Assets redeemable for `shares`, rounded down in favour of the pool
+    pub fn convert_to_assets(&self, shares: Uint128) -> Uint128 {
+        if self.total_shares.is_zero() {
+            return shares;
+        }
+        shares.multiply_ratio(self.total_assets, self.total_shares)
+    }
+
+    // Shares burned to withdraw exactly `assets`, rounded up in favour of the pool
+    pub fn shares_to_withdraw(&self, assets: Uint128) -> StdResult<Uint128> {
+        if self.total_shares.is_zero() || self.total_assets.is_zero() {
+            return Ok(assets);
+        }
+        let total_assets = Uint256::from(self.total_assets);
+        let shares = (assets.full_mul(self.total_shares) + total_assets - Uint256::one()) / total_assets;
+        Ok(Uint128::try_from(shares)?)
+    }
 }
\ No newline at end of file